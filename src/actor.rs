@@ -0,0 +1,251 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Instant;
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use crate::disk::Disk;
+use crate::saver::{OnSaved, Saved};
+use crate::time::Clock;
+use crate::{DataError, Metadata};
+
+enum Message<T> {
+  /// Runs on the data, returning whether it was modified.
+  Run(Box<dyn FnOnce(&mut T) -> bool + Send>),
+  Flush(oneshot::Sender<Result<(), DataError>>),
+}
+
+/// A handle to data owned by a single task, see [`crate::Builder::open_actor`].
+///
+/// Closures sent through [`DatabaseActor::read`] and [`DatabaseActor::update`] run on the task one
+/// at a time in the order they were sent, so there are no locks to hold or poison. The data is
+/// saved after each batch of updates, and a last time once every handle is dropped.
+///
+/// A failed save is retried after the next batch, use [`DatabaseActor::flush`] to find out
+/// whether the data was saved.
+pub struct DatabaseActor<T>(mpsc::UnboundedSender<Message<T>>);
+
+impl<T> Clone for DatabaseActor<T> {
+  fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
+/// The version being saved, when it started and how many bytes were written.
+type Saving = (u64, Instant, Result<usize, DataError>);
+
+/// The state owned by the actor's task.
+pub(crate) struct Actor<T> {
+  pub data: T,
+  pub metadata: Metadata,
+  pub disk: Arc<Disk>,
+  pub clock: Arc<dyn Clock>,
+  pub on_saved: Option<OnSaved>,
+  /// Stamped into the metadata on every save, see [`crate::Builder::app_version`].
+  pub app_version: Option<String>,
+  /// Bumped by every batch that modified the data.
+  version: u64,
+  /// The last version that made it to the file.
+  saved: u64,
+  saving: Option<task::JoinHandle<Saving>>,
+}
+
+impl<T: Serialize + Send + 'static> Actor<T> {
+  pub fn new(data: T, metadata: Metadata, disk: Arc<Disk>, clock: Arc<dyn Clock>) -> Self {
+    Self {
+      data,
+      metadata,
+      disk,
+      clock,
+      on_saved: None,
+      app_version: None,
+      version: 0,
+      saved: 0,
+      saving: None,
+    }
+  }
+
+  /// Waits for the save in progress.
+  async fn finish(&mut self) -> Result<(), DataError> {
+    let Some(saving) = self.saving.take() else {
+      return Ok(());
+    };
+    let (version, start, saved) = saving.await.map_err(std::io::Error::other)?;
+    let bytes = saved?;
+    self.saved = version;
+    if let Some(f) = &self.on_saved {
+      f(&Saved {
+        version,
+        bytes: Some(bytes),
+        duration: start.elapsed(),
+      });
+    }
+    Ok(())
+  }
+
+  /// Starts saving on a blocking thread if the data changed since the last save.
+  fn start(&mut self) -> Result<(), DataError> {
+    if self.version == self.saved || self.saving.is_some() {
+      return Ok(());
+    }
+    let start = Instant::now();
+    self.metadata.modified = self.clock.now();
+    if self.app_version.is_some() {
+      self.metadata.app_version.clone_from(&self.app_version);
+    }
+    let payload = self.disk.serialize(&self.data)?;
+    let (disk, metadata, version) = (self.disk.clone(), self.metadata.clone(), self.version);
+    self.saving = Some(task::spawn_blocking(move || {
+      (version, start, disk.save_payload(payload, &metadata))
+    }));
+    Ok(())
+  }
+
+  async fn flush(&mut self) -> Result<(), DataError> {
+    // a failed save is still dirty, so its error is replaced by the retry's
+    let _ = self.finish().await;
+    self.start()?;
+    self.finish().await
+  }
+}
+
+impl<T: Serialize + Send + 'static> DatabaseActor<T> {
+  pub(crate) fn spawn(mut actor: Actor<T>) -> Self {
+    let (tx, mut rx) = mpsc::unbounded_channel::<Message<T>>();
+    tokio::spawn(async move {
+      while let Some(message) = rx.recv().await {
+        let mut next = Some(message);
+        while let Some(message) = next.take().or_else(|| rx.try_recv().ok()) {
+          match message {
+            Message::Run(f) => {
+              if f(&mut actor.data) {
+                actor.version += 1;
+              }
+            }
+            Message::Flush(tx) => {
+              let _ = tx.send(actor.flush().await);
+            }
+          }
+        }
+        // a failed save stays dirty and is retried by the next batch or flush
+        if actor.finish().await.is_ok() {
+          let _ = actor.start();
+        }
+      }
+      let _ = actor.flush().await;
+    });
+    Self(tx)
+  }
+
+  /// Runs `f` on the data. A panic in `f` is resumed in the caller instead of on the task.
+  pub async fn read<R, F>(&self, f: F) -> R
+  where
+    R: Send + 'static,
+    F: FnOnce(&T) -> R + Send + 'static,
+  {
+    self.send(move |t| f(t), false).await
+  }
+
+  /// Runs `f` on the data and then saves it.
+  ///
+  /// A panic in `f` is resumed in the caller, and the data is still saved since `f` may have
+  /// modified it.
+  pub async fn update<R, F>(&self, f: F) -> R
+  where
+    R: Send + 'static,
+    F: FnOnce(&mut T) -> R + Send + 'static,
+  {
+    self.send(f, true).await
+  }
+
+  /// Saves the data if it changed, waiting for it to be written, and returns the error if saving
+  /// failed.
+  pub async fn flush(&self) -> Result<(), DataError> {
+    let (tx, rx) = oneshot::channel();
+    let _ = self.0.send(Message::Flush(tx));
+    rx.await.expect("database actor stopped")
+  }
+
+  async fn send<R, F>(&self, f: F, dirty: bool) -> R
+  where
+    R: Send + 'static,
+    F: FnOnce(&mut T) -> R + Send + 'static,
+  {
+    let (tx, rx) = oneshot::channel::<Result<R, Box<dyn Any + Send>>>();
+    let _ = self.0.send(Message::Run(Box::new(move |t| {
+      let _ = tx.send(panic::catch_unwind(AssertUnwindSafe(|| f(t))));
+      dirty
+    })));
+    match rx.await.expect("database actor stopped") {
+      Ok(r) => r,
+      Err(e) => panic::resume_unwind(e),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::Mutex;
+  use crate::Database;
+  use super::*;
+
+  #[tokio::test]
+  async fn test() {
+    let path = std::env::temp_dir().join("floppadb-actor.db");
+    let _ = std::fs::remove_file(&path);
+    let saved = Arc::new(Mutex::new(Vec::new()));
+    let s = saved.clone();
+    let db = Database::<Vec<u32>>::builder()
+      .on_saved(move |saved| s.lock().unwrap().push(saved.version))
+      .open_actor(&path)
+      .await
+      .unwrap();
+    let d = db.clone();
+    tokio::spawn(async move { d.update(|t| t.push(1)).await })
+      .await
+      .unwrap();
+    assert_eq!(db.update(|t| t.len()).await, 1);
+    assert_eq!(db.read(|t| t.clone()).await, [1]);
+
+    let d = db.clone();
+    let panicked = tokio::spawn(async move {
+      d.update(|t| {
+        t.push(2);
+        panic!("oops")
+      })
+      .await
+    });
+    assert!(panicked.await.unwrap_err().is_panic());
+    assert_eq!(db.read(|t| t.clone()).await, [1, 2]);
+    db.flush().await.unwrap();
+    assert_eq!(Database::<Vec<u32>>::new(&path).unwrap().get()[..], [1, 2]);
+    assert_eq!(saved.lock().unwrap().last(), Some(&3));
+
+    assert!(matches!(
+      Database::<Vec<u32>>::builder()
+        .manual_save()
+        .open_actor(&path)
+        .await,
+      Err(DataError::Unsupported("save_policy"))
+    ));
+  }
+
+  #[tokio::test]
+  async fn retry() {
+    let dir = std::env::temp_dir().join("floppadb-actor");
+    let _ = std::fs::create_dir(&dir);
+    let path = dir.join("db");
+    let _ = std::fs::remove_file(&path);
+    let db = Database::<Vec<u32>>::builder()
+      .open_actor(&path)
+      .await
+      .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    db.update(|t| t.push(1)).await;
+    assert!(db.flush().await.is_err());
+    std::fs::create_dir(&dir).unwrap();
+    db.flush().await.unwrap();
+    assert_eq!(Database::<Vec<u32>>::new(&path).unwrap().get()[..], [1]);
+  }
+}
@@ -0,0 +1,235 @@
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::disk::write_atomic;
+use crate::DataError;
+
+/// A database for data that is mostly appended to, like logs, histories and queues.
+///
+/// Each append writes just the new item to the end of the file, which is only rewritten by
+/// [`AppendLog::truncate`] and [`AppendLog::compact`]. Items are stored as length prefixed bincode
+/// records without a header, so the file can't be compressed, encrypted or signed.
+pub struct AppendLog<T> {
+  path: PathBuf,
+  log: RwLock<Log<T>>,
+}
+
+struct Log<T> {
+  file: File,
+  items: VecDeque<T>,
+  max_len: Option<usize>,
+  /// Records still in the file that were dropped from the front of a ring.
+  dead: usize,
+}
+
+impl<T: Serialize + DeserializeOwned> AppendLog<T> {
+  /// Opens the log at `path`, creating it if it doesn't exist.
+  ///
+  /// A record cut short or left as garbage by a crash during an append, e.g. zeros when the file
+  /// grew before its data was written, is dropped along with anything after it.
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DataError> {
+    let path = path.as_ref().to_path_buf();
+    let bytes = match fs::read(&path) {
+      Ok(bytes) => bytes,
+      Err(e) if e.kind() == ErrorKind::NotFound => vec![],
+      Err(e) => return Err(e.into()),
+    };
+    let mut items = VecDeque::new();
+    let mut rest = &bytes[..];
+    while let Some((len, record)) = rest.split_first_chunk::<4>() {
+      let len = u32::from_le_bytes(*len) as usize;
+      let Some((item, next)) = record.split_at_checked(len).filter(|_| len > 0) else {
+        break;
+      };
+      let Ok(item) = bincode::deserialize(item) else {
+        break;
+      };
+      items.push_back(item);
+      rest = next;
+    }
+    let file = File::options().create(true).append(true).open(&path)?;
+    if !rest.is_empty() {
+      file.set_len((bytes.len() - rest.len()) as u64)?;
+    }
+    Ok(Self {
+      path,
+      log: RwLock::new(Log {
+        file,
+        items,
+        max_len: None,
+        dead: 0,
+      }),
+    })
+  }
+
+  /// Turns the log into a ring that keeps only the last `max_len` items.
+  ///
+  /// Older items are dropped straight away but stay in the file until there are `max_len` of
+  /// them, when it is compacted.
+  pub fn ring(self, max_len: usize) -> Result<Self, DataError> {
+    {
+      let mut log = self.log.write().unwrap();
+      log.max_len = Some(max_len.max(1));
+      log.trim();
+    }
+    self.maybe_compact()?;
+    Ok(self)
+  }
+
+  /// Appends `item` to the end of the file, returning once it is synced to disk.
+  ///
+  /// If the write fails the file is cut back to its previous length, so a partly written record
+  /// doesn't end up in front of the next one.
+  pub fn append(&self, item: T) -> Result<(), DataError> {
+    {
+      let mut log = self.log.write().unwrap();
+      let record = record(&item)?;
+      let len = log.file.metadata()?.len();
+      let written = log
+        .file
+        .write_all(&record)
+        .and_then(|()| log.file.sync_data());
+      if let Err(e) = written {
+        log.file.set_len(len)?;
+        return Err(e.into());
+      }
+      log.items.push_back(item);
+      log.trim();
+    }
+    self.maybe_compact()
+  }
+
+  guard_fn! {
+    /// Locks the log for reading.
+    fn get(&self) -> AppendGuard<'_, T> {
+      AppendGuard(self.log.read().unwrap())
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.get().len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.get().is_empty()
+  }
+
+  /// Keeps the first `len` items and rewrites the file.
+  pub fn truncate(&self, len: usize) -> Result<(), DataError> {
+    let mut log = self.log.write().unwrap();
+    log.items.truncate(len);
+    self.rewrite(&mut log)
+  }
+
+  /// Rewrites the file with only the current items, dropping the records of removed ones.
+  pub fn compact(&self) -> Result<(), DataError> {
+    self.rewrite(&mut self.log.write().unwrap())
+  }
+
+  fn maybe_compact(&self) -> Result<(), DataError> {
+    let mut log = self.log.write().unwrap();
+    match log.max_len {
+      Some(max_len) if log.dead >= max_len => self.rewrite(&mut log),
+      _ => Ok(()),
+    }
+  }
+
+  fn rewrite(&self, log: &mut Log<T>) -> Result<(), DataError> {
+    let mut bytes = vec![];
+    for item in &log.items {
+      bytes.extend(record(item)?);
+    }
+    write_atomic(&self.path, &bytes)?;
+    log.file = File::options().append(true).open(&self.path)?;
+    log.dead = 0;
+    Ok(())
+  }
+}
+
+/// Serializes `item` behind its length.
+fn record<T: Serialize>(item: &T) -> Result<Vec<u8>, DataError> {
+  let mut record = vec![0; 4];
+  bincode::serialize_into(&mut record, item)?;
+  let len = record.len() as u32 - 4;
+  record[..4].copy_from_slice(&len.to_le_bytes());
+  Ok(record)
+}
+
+impl<T> Log<T> {
+  fn trim(&mut self) {
+    if let Some(max_len) = self.max_len {
+      while self.items.len() > max_len {
+        self.items.pop_front();
+        self.dead += 1;
+      }
+    }
+  }
+}
+
+/// Derefs to the items, oldest first.
+pub struct AppendGuard<'a, T>(RwLockReadGuard<'a, Log<T>>);
+
+impl<T> Deref for AppendGuard<'_, T> {
+  type Target = VecDeque<T>;
+
+  fn deref(&self) -> &VecDeque<T> {
+    &self.0.items
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-append.db");
+    let _ = fs::remove_file(&path);
+    let log = AppendLog::<String>::open(&path).unwrap();
+    for i in 0..3 {
+      log.append(i.to_string()).unwrap();
+    }
+    drop(log);
+    let len = fs::metadata(&path).unwrap().len();
+    File::options()
+      .append(true)
+      .open(&path)
+      .unwrap()
+      .write_all(&[9, 0, 0, 0, 1])
+      .unwrap();
+
+    let log = AppendLog::<String>::open(&path).unwrap();
+    assert_eq!(log.get().iter().collect::<Vec<_>>(), ["0", "1", "2"]);
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    drop(log);
+    // a zero length prefix, and a record that doesn't decode
+    for tail in [&[0; 8][..], &[2, 0, 0, 0, 0xff, 0xff]] {
+      File::options()
+        .append(true)
+        .open(&path)
+        .unwrap()
+        .write_all(tail)
+        .unwrap();
+      let log = AppendLog::<String>::open(&path).unwrap();
+      assert_eq!(log.len(), 3);
+      assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+
+    let log = AppendLog::<String>::open(&path).unwrap();
+    log.truncate(1).unwrap();
+    log.append("3".to_string()).unwrap();
+    assert_eq!(log.len(), 2);
+
+    let log = AppendLog::<String>::open(&path).unwrap().ring(2).unwrap();
+    for i in 4..7 {
+      log.append(i.to_string()).unwrap();
+    }
+    assert_eq!(log.get().iter().collect::<Vec<_>>(), ["5", "6"]);
+    let log = AppendLog::<String>::open(&path).unwrap();
+    assert_eq!(log.get().iter().collect::<Vec<_>>(), ["4", "5", "6"]);
+  }
+}
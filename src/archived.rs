@@ -0,0 +1,113 @@
+use std::fs::File;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::{RwLock, RwLockReadGuard};
+use memmap2::Mmap;
+use rkyv::api::high::{HighDeserializer, HighSerializer, HighValidator};
+use rkyv::rancor::Error;
+use rkyv::ser::allocator::ArenaHandle;
+use rkyv::util::AlignedVec;
+use rkyv::{Archive, Deserialize, Serialize};
+use crate::disk::write_atomic;
+use crate::DataError;
+
+/// A database that reads its data in place from the memory-mapped rkyv archive.
+///
+/// Reads don't deserialize anything, which suits large data that is mostly read. Each write
+/// deserializes the data, changes it, archives it again and rewrites the file before returning.
+///
+/// The archive is validated once when opening. The file is a bare archive without a header, so
+/// it can't be compressed, encrypted or signed.
+pub struct ArchivedDatabase<T> {
+  path: PathBuf,
+  map: RwLock<Mmap>,
+  _data: PhantomData<T>,
+}
+
+impl<T> ArchivedDatabase<T>
+where
+  T: Archive + Default + for<'a> Serialize<HighSerializer<AlignedVec, ArenaHandle<'a>, Error>>,
+  T::Archived: for<'a> rkyv::bytecheck::CheckBytes<HighValidator<'a, Error>>
+    + Deserialize<T, HighDeserializer<Error>>,
+{
+  /// Opens the archive at `path`, writing `T::default()` if it doesn't exist.
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DataError> {
+    let path = path.as_ref().to_path_buf();
+    if !path.exists() {
+      write_atomic(&path, &rkyv::to_bytes::<Error>(&T::default())?)?;
+    }
+    let map = map(&path)?;
+    rkyv::access::<T::Archived, Error>(&map)?;
+    Ok(Self {
+      path,
+      map: RwLock::new(map),
+      _data: PhantomData,
+    })
+  }
+
+  guard_fn! {
+    fn get(&self) -> ArchivedGuard<'_, T> {
+      ArchivedGuard(self.map.read().unwrap(), PhantomData)
+    }
+  }
+
+  /// Calls `f` with the archived data, see [`crate::Database::with_read`].
+  pub fn with_read<R, F: FnOnce(&T::Archived) -> R>(&self, f: F) -> R {
+    f(&self.get())
+  }
+
+  /// Deserializes the data, calls `f` with it and then saves it.
+  ///
+  /// Reads wait until the file has been rewritten.
+  pub fn update<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> Result<R, DataError> {
+    let mut map = self.map.write().unwrap();
+    // safety: the archive was validated when it was mapped
+    let archived = unsafe { rkyv::access_unchecked::<T::Archived>(&map) };
+    let mut data = rkyv::deserialize::<T, Error>(archived)?;
+    let r = f(&mut data);
+    write_atomic(&self.path, &rkyv::to_bytes::<Error>(&data)?)?;
+    *map = self::map(&self.path)?;
+    Ok(r)
+  }
+}
+
+fn map(path: &Path) -> Result<Mmap, DataError> {
+  // safety: the file is only ever replaced by renaming over it, never modified in place
+  Ok(unsafe { Mmap::map(&File::open(path)?)? })
+}
+
+/// Derefs to the archived data, straight from the mapped file.
+pub struct ArchivedGuard<'a, T>(RwLockReadGuard<'a, Mmap>, PhantomData<T>);
+
+impl<T: Archive> Deref for ArchivedGuard<'_, T> {
+  type Target = T::Archived;
+
+  fn deref(&self) -> &T::Archived {
+    // safety: the archive was validated when it was mapped
+    unsafe { rkyv::access_unchecked::<T::Archived>(&self.0) }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[derive(Archive, Serialize, Deserialize, Default)]
+  struct Test {
+    names: Vec<String>,
+  }
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-archived.db");
+    let _ = std::fs::remove_file(&path);
+    let db = ArchivedDatabase::<Test>::open(&path).unwrap();
+    assert!(db.get().names.is_empty());
+    db.update(|t| t.names.push("floppa".to_string())).unwrap();
+    assert_eq!(db.get().names[0], "floppa");
+
+    let db = ArchivedDatabase::<Test>::open(&path).unwrap();
+    assert_eq!(db.get().names.len(), 1);
+  }
+}
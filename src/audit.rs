@@ -0,0 +1,147 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use sha2::{Digest, Sha256};
+use crate::DataError;
+
+/// An append-only log of committed writes.
+///
+/// Each line is `version, unix millis, label, hash` separated by tabs, where the hash covers the
+/// rest of the line and the previous hash. Editing, removing or reordering lines breaks the chain,
+/// although anyone with write access can still recompute it from scratch.
+pub(crate) struct AuditLog {
+  file: File,
+  hash: String,
+  /// Lines that couldn't be written yet, retried by the next record.
+  pending: String,
+}
+
+impl AuditLog {
+  /// Opens or creates the log at `path`, returning it with the last recorded version.
+  pub fn open(path: &Path) -> Result<(Self, u64), DataError> {
+    let (hash, version) = match File::open(path) {
+      Ok(f) => read_chain(f)?.unwrap_or_default(),
+      Err(_) => Default::default(),
+    };
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok((
+      Self {
+        file,
+        hash,
+        pending: String::new(),
+      },
+      version,
+    ))
+  }
+
+  /// Records a write at `now`. If writing fails the entry is kept and written before the next
+  /// one, so the chain stays intact.
+  pub fn record(
+    &mut self,
+    version: u64,
+    label: Option<&str>,
+    now: SystemTime,
+  ) -> Result<(), DataError> {
+    let millis = now
+      .duration_since(UNIX_EPOCH)
+      .unwrap_or_default()
+      .as_millis();
+    let label: String = label
+      .unwrap_or_default()
+      .chars()
+      .map(|c| if c.is_control() { ' ' } else { c })
+      .collect();
+    let entry = format!("{}\t{}\t{}", version, millis, label);
+    self.hash = chain(&self.hash, &entry);
+    self.pending += &format!("{}\t{}\n", entry, self.hash);
+    // written piece by piece so a failed write doesn't repeat the part that made it
+    while !self.pending.is_empty() {
+      match self.file.write(self.pending.as_bytes()) {
+        Ok(0) => return Err(std::io::Error::from(ErrorKind::WriteZero).into()),
+        Ok(n) => drop(self.pending.drain(..n)),
+        Err(e) if e.kind() == ErrorKind::Interrupted => {}
+        Err(e) => return Err(e.into()),
+      }
+    }
+    Ok(())
+  }
+}
+
+fn chain(prev: &str, entry: &str) -> String {
+  Sha256::new()
+    .chain_update(prev)
+    .chain_update(entry)
+    .finalize()
+    .iter()
+    .map(|b| format!("{:02x}", b))
+    .collect()
+}
+
+/// Walks the chain, returning the last hash and version, or `None` if the log is empty.
+fn read_chain(f: File) -> Result<Option<(String, u64)>, DataError> {
+  let mut last = None;
+  let mut hash = String::new();
+  for (i, line) in BufReader::new(f).lines().enumerate() {
+    let line = line?;
+    let (entry, expected) = line.rsplit_once('\t').ok_or(DataError::Tampered(i + 1))?;
+    let version = entry
+      .split('\t')
+      .next()
+      .and_then(|v| v.parse().ok())
+      .ok_or(DataError::Tampered(i + 1))?;
+    hash = chain(&hash, entry);
+    if hash != expected {
+      return Err(DataError::Tampered(i + 1));
+    }
+    last = Some(version);
+  }
+  Ok(last.map(|v| (hash, v)))
+}
+
+/// Checks that the audit log at `path` hasn't been modified, returning the last recorded version.
+pub fn verify_audit_log<P: AsRef<Path>>(path: P) -> Result<u64, DataError> {
+  Ok(read_chain(File::open(path)?)?.map_or(0, |(_, v)| v))
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+  use crate::Database;
+  use super::*;
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-audit.log");
+    let _ = fs::remove_file(&path);
+    let db = Database::<u32>::builder()
+      .audit_log(&path)
+      .build(0, |_| {})
+      .unwrap();
+    *db.get_mut() += 1;
+    *db.get_mut_labeled("reset") = 0;
+    assert_eq!(verify_audit_log(&path).unwrap(), 2);
+
+    let log = fs::read_to_string(&path).unwrap().replace("reset", "other");
+    fs::write(&path, log).unwrap();
+    assert!(matches!(
+      verify_audit_log(&path),
+      Err(DataError::Tampered(2))
+    ));
+  }
+
+  #[cfg(target_os = "linux")]
+  #[test]
+  fn retry() {
+    let path = std::env::temp_dir().join("floppadb-audit-retry.log");
+    let _ = fs::remove_file(&path);
+    let (mut log, _) = AuditLog::open(&path).unwrap();
+    let file = std::mem::replace(&mut log.file, File::create("/dev/full").unwrap());
+    let now = UNIX_EPOCH + std::time::Duration::from_secs(1);
+    assert!(log.record(1, None, now).is_err());
+    log.file = file;
+    log.record(2, Some("after"), now).unwrap();
+    assert_eq!(verify_audit_log(&path).unwrap(), 2);
+    assert!(fs::read_to_string(&path).unwrap().starts_with("1\t1000\t"));
+  }
+}
@@ -0,0 +1,217 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime};
+use serde::Serialize;
+use crate::disk::write_atomic;
+use crate::{Database, DataError};
+
+/// Timestamped copies of a database written on a schedule, see [`Database::schedule_backups`].
+///
+/// Backups are named `{prefix}-{unix millis}.db` and encoded like the database file, so they are
+/// still encrypted and opened with the same builder.
+#[derive(Clone, Debug)]
+pub struct Backups {
+  dir: PathBuf,
+  prefix: String,
+  every: Duration,
+  retention: Retention,
+}
+
+/// Limits on the backups kept, enforced after each backup, see [`Backups::retention`].
+///
+/// Backups are kept newest first while they are within every limit, the rest are deleted. The
+/// newest backup is always kept.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct Retention {
+  max_count: Option<usize>,
+  max_age: Option<Duration>,
+  max_bytes: Option<u64>,
+}
+
+impl Retention {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn max_count(mut self, count: usize) -> Self {
+    self.max_count = Some(count);
+    self
+  }
+
+  pub fn max_age(mut self, age: Duration) -> Self {
+    self.max_age = Some(age);
+    self
+  }
+
+  /// Limits the total size of the backups.
+  pub fn max_bytes(mut self, bytes: u64) -> Self {
+    self.max_bytes = Some(bytes);
+    self
+  }
+}
+
+impl Backups {
+  /// Backs up to `dir` every `every`, creating it if needed.
+  pub fn new<P: AsRef<Path>>(dir: P, every: Duration) -> Self {
+    Self {
+      dir: dir.as_ref().to_path_buf(),
+      prefix: "backup".to_string(),
+      every,
+      retention: Retention::default(),
+    }
+  }
+
+  /// Names backups `{prefix}-{unix millis}.db`, so several databases can share a directory.
+  pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+    self.prefix = prefix.into();
+    self
+  }
+
+  /// Deletes backups that fall outside `retention` after each backup.
+  pub fn retention(mut self, retention: Retention) -> Self {
+    self.retention = retention;
+    self
+  }
+
+  /// Shorthand for a [`Retention`] with only a max age, e.g. 14 days.
+  pub fn keep(self, age: Duration) -> Self {
+    self.retention(Retention::new().max_age(age))
+  }
+
+  /// The backups in the directory with their times, oldest first.
+  pub fn list(&self) -> Result<Vec<(PathBuf, SystemTime)>, DataError> {
+    let entries = match fs::read_dir(&self.dir) {
+      Ok(entries) => entries,
+      Err(e) if e.kind() == ErrorKind::NotFound => return Ok(vec![]),
+      Err(e) => return Err(e.into()),
+    };
+    let mut backups = vec![];
+    for entry in entries {
+      let path = entry?.path();
+      let time = path
+        .file_name()
+        .and_then(|n| n.to_str())
+        .and_then(|n| n.strip_prefix(&self.prefix)?.strip_prefix('-'))
+        .and_then(|n| n.strip_suffix(".db")?.parse().ok())
+        .map(|ms| SystemTime::UNIX_EPOCH + Duration::from_millis(ms));
+      if let Some(time) = time {
+        backups.push((path, time));
+      }
+    }
+    backups.sort_by_key(|(_, time)| *time);
+    Ok(backups)
+  }
+
+  /// The backups that [`Backups::prune`] would delete now, without deleting them.
+  pub fn to_prune(&self) -> Result<Vec<PathBuf>, DataError> {
+    let Retention {
+      max_count,
+      max_age,
+      max_bytes,
+    } = self.retention;
+    let now = SystemTime::now();
+    let mut bytes = 0;
+    let mut pruned = vec![];
+    for (i, (path, time)) in self.list()?.into_iter().rev().enumerate() {
+      bytes += fs::metadata(&path)?.len();
+      let keep = i == 0
+        || (pruned.is_empty()
+          && max_count.is_none_or(|max| i < max)
+          && max_age.is_none_or(|max| now.duration_since(time).is_ok_and(|age| age <= max))
+          && max_bytes.is_none_or(|max| bytes <= max));
+      if !keep {
+        pruned.push(path);
+      }
+    }
+    Ok(pruned)
+  }
+
+  /// Deletes the backups that fall outside the retention, returning their paths.
+  pub fn prune(&self) -> Result<Vec<PathBuf>, DataError> {
+    let pruned = self.to_prune()?;
+    for path in &pruned {
+      fs::remove_file(path)?;
+    }
+    Ok(pruned)
+  }
+
+  fn run<T: Serialize + Send + Sync + 'static>(&self, db: &Database<T>) -> Result<(), DataError> {
+    fs::create_dir_all(&self.dir)?;
+    let ms = SystemTime::now()
+      .duration_since(SystemTime::UNIX_EPOCH)
+      .unwrap()
+      .as_millis();
+    db.backup_now(self.dir.join(format!("{}-{ms}.db", self.prefix)))?;
+    self.prune()?;
+    Ok(())
+  }
+}
+
+impl<T: Serialize + Send + Sync + 'static> Database<T> {
+  /// Writes a copy of the database to `path`, e.g. before a risky migration.
+  ///
+  /// The copy is encoded exactly like the file, see [`Database::to_bytes`].
+  pub fn backup_now<P: AsRef<Path>>(&self, path: P) -> Result<(), DataError> {
+    write_atomic(path.as_ref(), &self.to_bytes()?)
+  }
+
+  /// Spawns a thread that backs the database up following `backups`, independently of saves.
+  ///
+  /// A backup is skipped when nothing was written since the last one. Failed backups are
+  /// logged as warnings through the `log` crate and retried next time. The thread stops once the database is dropped.
+  pub fn schedule_backups(&self, backups: Backups) {
+    let weak = Arc::downgrade(&self.0);
+    let mut version = None;
+    thread::spawn(move || loop {
+      thread::sleep(backups.every);
+      let Some(inner) = weak.upgrade() else {
+        break;
+      };
+      let db = Database(inner);
+      if version == Some(db.version()) {
+        continue;
+      }
+      match backups.run(&db) {
+        Ok(()) => version = Some(db.version()),
+        Err(e) => log::warn!("backup to {} failed: {e}", backups.dir.display()),
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let dir = std::env::temp_dir().join("floppadb-backups");
+    let _ = fs::remove_dir_all(&dir);
+    let db = Database::new_custom(vec![1u32], |_| {});
+    let backups = Backups::new(&dir, Duration::from_millis(10)).keep(Duration::from_secs(60));
+    db.schedule_backups(backups.clone());
+    while backups.list().unwrap().is_empty() {
+      thread::sleep(Duration::from_millis(1));
+    }
+    let (path, _) = &backups.list().unwrap()[0];
+    let bytes = fs::read(path).unwrap();
+    let builder = Database::<Vec<u32>>::builder();
+    assert_eq!(builder.from_bytes(&bytes).unwrap().0, [1]);
+
+    db.backup_now(dir.join("migration.db")).unwrap();
+    assert!(dir.join("migration.db").exists());
+    assert_eq!(backups.list().unwrap().len(), 1);
+
+    for ms in [1, 2, 3] {
+      fs::write(dir.join(format!("backup-{ms}.db")), [0; 10]).unwrap();
+    }
+    let backups = backups.retention(Retention::new().max_count(3));
+    assert_eq!(backups.to_prune().unwrap(), [dir.join("backup-1.db")]);
+    let backups = backups.keep(Duration::from_secs(60));
+    assert_eq!(backups.prune().unwrap().len(), 3);
+    assert_eq!(backups.list().unwrap().len(), 1);
+  }
+}
@@ -0,0 +1,56 @@
+use std::path::Path;
+use borsh::{BorshDeserialize, BorshSerialize};
+use crate::{Builder, Database, DataError};
+
+impl<T: BorshSerialize + BorshDeserialize + Default + Send + Sync + 'static> Builder<T> {
+  /// Like [`Builder::open`], but the data is serialized with Borsh instead of bincode, for types
+  /// that already derive it.
+  pub fn open_borsh<P: AsRef<Path>>(self, path: P) -> Result<Database<T>, DataError> {
+    self.open_encoded(
+      path.as_ref(),
+      false,
+      |payload| Ok(borsh::from_slice(payload)?),
+      |data| Ok(borsh::to_vec(data)?),
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[derive(BorshSerialize, BorshDeserialize, serde::Serialize, Default, PartialEq, Debug)]
+  struct Test {
+    names: Vec<String>,
+  }
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-borsh.db");
+    let _ = std::fs::remove_file(&path);
+    let db = Database::<Test>::builder()
+      .manual_save()
+      .app_version("1.0")
+      .open_borsh(&path)
+      .unwrap();
+    db.get_mut().names.push("floppa".to_string());
+    db.flush().unwrap();
+
+    let db = Database::<Test>::builder().open_borsh(&path).unwrap();
+    assert_eq!(db.get().names, ["floppa"]);
+    assert_eq!(db.metadata().app_version.as_deref(), Some("1.0"));
+
+    // backups are encoded with borsh too, so a truncated file can be restored from them
+    let dir = std::env::temp_dir().join("floppadb-borsh-backups");
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    db.backup_now(dir.join("backup-1.db")).unwrap();
+    std::fs::write(&path, []).unwrap();
+    let backups = crate::Backups::new(&dir, std::time::Duration::from_secs(60));
+    let db = Database::<Test>::builder()
+      .on_truncated(crate::OnTruncated::Restore(backups))
+      .open_borsh(&path)
+      .unwrap();
+    assert_eq!(db.get().names, ["floppa"]);
+  }
+}
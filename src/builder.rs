@@ -0,0 +1,760 @@
+use std::marker::PhantomData;
+#[cfg(feature = "bincode")]
+use std::io::{self, Read, Write};
+#[cfg(feature = "bincode")]
+use std::mem;
+use std::sync::Arc;
+use std::time::Duration;
+#[cfg(any(feature = "bincode", feature = "audit"))]
+use std::path::{Path, PathBuf};
+#[cfg(feature = "bincode")]
+use serde::{Serialize, de::DeserializeOwned, de::DeserializeSeed};
+#[cfg(feature = "bincode")]
+use bincode::Options;
+#[cfg(feature = "encryption")]
+use crate::crypto::Secret;
+#[cfg(feature = "bincode")]
+use crate::canonical;
+#[cfg(feature = "bincode")]
+use crate::compression::Compression;
+#[cfg(feature = "zstd")]
+use crate::compression::Dictionary;
+#[cfg(feature = "bincode")]
+use crate::profile::Profiler;
+#[cfg(feature = "bincode")]
+use crate::format::Format;
+#[cfg(feature = "bincode")]
+use crate::disk::{Disk, OnTruncated, OpenOptions};
+#[cfg(feature = "signing")]
+use crate::format::OnTamper;
+#[cfg(feature = "tokio")]
+use crate::actor::{Actor, DatabaseActor};
+#[cfg(feature = "testing")]
+use crate::testing::FaultInjector;
+use crate::saver::{self, DeadSaver, SavePolicy, Saved, SaverPanic};
+use crate::time::{Clock, Scheduler, SystemClock};
+use crate::{Database, DataError, Metadata};
+
+/// Decides whether a file saved by an app version can be opened, see
+/// [`Builder::check_app_version`].
+type CheckVersion = Box<dyn Fn(&str) -> bool + Send + Sync>;
+
+/// Serializes the payload of a database opened with another encoding than bincode.
+#[cfg(feature = "bincode")]
+pub(crate) type Encode<T> = Arc<dyn Fn(&T) -> Result<Vec<u8>, DataError> + Send + Sync>;
+
+/// Configures a [`Database`] before it is opened.
+pub struct Builder<T> {
+  #[cfg(feature = "audit")]
+  pub(crate) audit_log: Option<PathBuf>,
+  pub(crate) app_version: Option<String>,
+  check_app_version: Option<CheckVersion>,
+  pub(crate) saver: saver::Options,
+  pub(crate) on_dead_saver: Option<DeadSaver>,
+  pub(crate) on_saved: Option<saver::OnSaved>,
+  pub(crate) warn_held: Option<Duration>,
+  pub(crate) clock: Arc<dyn Clock>,
+  pub(crate) register: bool,
+  #[cfg(feature = "bincode")]
+  format: Format,
+  #[cfg(feature = "bincode")]
+  snapshot_every: Option<u32>,
+  #[cfg(feature = "mmap")]
+  mmap: bool,
+  #[cfg(feature = "bincode")]
+  pub(crate) disk: Option<Arc<Disk>>,
+  #[cfg(feature = "bincode")]
+  pub(crate) encode: Option<Encode<T>>,
+  #[cfg(feature = "bincode")]
+  open_options: OpenOptions,
+  #[cfg(feature = "bincode")]
+  canonical: bool,
+  #[cfg(feature = "bincode")]
+  profile: Option<Duration>,
+  #[cfg(feature = "bincode")]
+  on_truncated: OnTruncated,
+  #[cfg(feature = "testing")]
+  faults: Option<FaultInjector>,
+  _data: PhantomData<T>,
+}
+
+impl<T: Send + Sync + 'static> Builder<T> {
+  pub fn new() -> Self {
+    Self {
+      #[cfg(feature = "audit")]
+      audit_log: None,
+      app_version: None,
+      check_app_version: None,
+      saver: saver::Options::default(),
+      on_dead_saver: None,
+      on_saved: None,
+      warn_held: None,
+      clock: Arc::new(SystemClock),
+      register: false,
+      #[cfg(feature = "bincode")]
+      format: Format::default(),
+      #[cfg(feature = "bincode")]
+      snapshot_every: None,
+      #[cfg(feature = "mmap")]
+      mmap: false,
+      #[cfg(feature = "bincode")]
+      disk: None,
+      #[cfg(feature = "bincode")]
+      encode: None,
+      #[cfg(feature = "bincode")]
+      open_options: OpenOptions::default(),
+      #[cfg(feature = "bincode")]
+      canonical: false,
+      #[cfg(feature = "bincode")]
+      profile: None,
+      #[cfg(feature = "bincode")]
+      on_truncated: OnTruncated::Fail,
+      #[cfg(feature = "testing")]
+      faults: None,
+      _data: PhantomData,
+    }
+  }
+
+  /// Records every committed write to an append-only log at `path`, see [`crate::verify_audit_log`].
+  ///
+  /// Opening fails if an existing log doesn't verify. Entries that fail to be written are retried
+  /// with the next write, and the error is shown in [`Database::saver_health`].
+  #[cfg(feature = "audit")]
+  pub fn audit_log<P: AsRef<Path>>(mut self, path: P) -> Self {
+    self.audit_log = Some(path.as_ref().to_path_buf());
+    self
+  }
+
+  /// Stamps the metadata with the version of the application on every save, e.g.
+  /// `env!("CARGO_PKG_VERSION")`.
+  ///
+  /// The version that saved the file stays in [`Database::metadata`] until the first save, and in
+  /// [`Database::loaded_metadata`] for good. Use [`crate::read_metadata`] to find out which version
+  /// last saved a file before opening it.
+  pub fn app_version<S: Into<String>>(mut self, version: S) -> Self {
+    self.app_version = Some(version.into());
+    self
+  }
+
+  /// Calls `f` with the app version that last saved the file when opening it, failing with
+  /// [`DataError::AppVersion`] if it returns false, e.g. for files saved by a newer version.
+  ///
+  /// Files saved without an app version are always opened.
+  pub fn check_app_version<F: Fn(&str) -> bool + Send + Sync + 'static>(mut self, f: F) -> Self {
+    self.check_app_version = Some(Box::new(f));
+    self
+  }
+
+  pub(crate) fn check_loaded(&self, metadata: &Metadata) -> Result<(), DataError> {
+    match (&self.check_app_version, &metadata.app_version) {
+      (Some(check), Some(version)) if !check(version) => {
+        Err(DataError::AppVersion(version.clone()))
+      }
+      _ => Ok(()),
+    }
+  }
+
+  /// Decides when the database is saved after a write, see [`SavePolicy`].
+  pub fn save_policy(mut self, policy: SavePolicy) -> Self {
+    self.saver.policy = policy;
+    self
+  }
+
+  /// Shorthand for [`SavePolicy::Manual`].
+  pub fn manual_save(self) -> Self {
+    self.save_policy(SavePolicy::Manual)
+  }
+
+  /// Names the saver thread, for debuggers and tools like `top`.
+  pub fn saver_name<S: Into<String>>(mut self, name: S) -> Self {
+    self.saver.name = Some(name.into());
+    self
+  }
+
+  /// Sets the niceness of the saver thread, e.g. 10 to save with a lower priority.
+  ///
+  /// Only supported on Linux, where each thread has its own niceness. Opening fails with
+  /// [`DataError::Unsupported`] if it is combined with [`Builder::scheduler`], since the saver
+  /// then runs on threads the scheduler owns.
+  pub fn saver_priority(mut self, niceness: i32) -> Self {
+    self.saver.priority = Some(niceness);
+    self
+  }
+
+  /// Decides what the saver thread does when saving panics, including when saving to a file
+  /// fails.
+  pub fn on_saver_panic(mut self, on_panic: SaverPanic) -> Self {
+    self.saver.on_panic = Some(on_panic);
+    self
+  }
+
+  /// Warns or panics on writes once the saver has stopped, e.g. after a save panicked, so it
+  /// doesn't go unnoticed that writes are no longer saved. See [`Database::saver_health`].
+  pub fn on_dead_saver(mut self, on_dead: DeadSaver) -> Self {
+    self.on_dead_saver = Some(on_dead);
+    self
+  }
+
+  /// Calls `f` after every successful save, e.g. to show that all changes are saved.
+  ///
+  /// It runs on the saver thread or task, or in [`Database::flush`].
+  pub fn on_saved<F: Fn(&Saved) + Send + Sync + 'static>(mut self, f: F) -> Self {
+    self.on_saved = Some(Box::new(f));
+    self
+  }
+
+  /// Compresses the payload before it is encrypted and written.
+  #[cfg(feature = "bincode")]
+  pub fn compression(mut self, compression: Compression) -> Self {
+    self.format.compression = compression;
+    self
+  }
+
+  /// Compresses with a shared `dictionary` when the compression is [`Compression::Zstd`].
+  ///
+  /// Files already compressed with it can only be opened with it.
+  #[cfg(feature = "zstd")]
+  pub fn zstd_dictionary(mut self, dictionary: Dictionary) -> Self {
+    self.format.dictionary = Some(Arc::new(dictionary));
+    self
+  }
+
+  /// Saves only the parts of the serialized data that changed since the last full snapshot to a
+  /// `.delta` file next to the database, for large data that changes a little at a time.
+  ///
+  /// A full snapshot is written every `snapshot_every` saves, or sooner when the changes grow
+  /// past half of the data. Opening applies the delta, even when this is no longer set.
+  ///
+  /// The whole data is still serialized for every save to find the chunks that changed, so this
+  /// saves on writing, not on serializing.
+  #[cfg(feature = "bincode")]
+  pub fn delta_saves(mut self, snapshot_every: u32) -> Self {
+    self.snapshot_every = Some(snapshot_every);
+    self
+  }
+
+  /// Memory-maps the file when opening instead of reading it into memory first.
+  ///
+  /// The data is deserialized straight from the mapping, so a large file is never held in memory
+  /// twice. Compressed or encrypted files still have to be decoded into a buffer.
+  #[cfg(feature = "mmap")]
+  pub fn mmap(mut self) -> Self {
+    self.mmap = true;
+    self
+  }
+
+  /// Encrypts the file with XChaCha20-Poly1305 using `key`.
+  ///
+  /// Opening an unencrypted file fails with [`DataError::Unencrypted`], unless
+  /// [`Builder::allow_unencrypted`] is set.
+  #[cfg(feature = "encryption")]
+  pub fn key(mut self, key: [u8; 32]) -> Self {
+    self.format.secret = Some(Secret::Key(key));
+    self
+  }
+
+  /// Like [`Builder::key`], but the key is derived from `password` with Argon2id.
+  ///
+  /// The salt and params are stored in the header, and the key is only derived once when opening.
+  #[cfg(feature = "encryption")]
+  pub fn password(mut self, password: &str) -> Self {
+    self.format.secret = Some(Secret::Password(password.to_string()));
+    self
+  }
+
+  /// Opens unencrypted files despite [`Builder::key`] or [`Builder::password`], encrypting them on
+  /// their next save, e.g. to migrate existing files to encryption.
+  #[cfg(feature = "encryption")]
+  pub fn allow_unencrypted(mut self) -> Self {
+    self.format.allow_unencrypted = true;
+    self
+  }
+
+  /// Appends an HMAC-SHA256 keyed with `key` to the file, and checks it when opening.
+  ///
+  /// The file stays readable without the key, but can't be modified without it going unnoticed.
+  /// Files without a signature are treated as tampered with.
+  #[cfg(feature = "signing")]
+  pub fn sign<K: AsRef<[u8]>>(mut self, key: K, on_tamper: OnTamper) -> Self {
+    self.format.signing = Some((key.as_ref().to_vec(), on_tamper));
+    self
+  }
+
+  /// Sets platform specific options for writing the file, see [`OpenOptions`].
+  #[cfg(feature = "bincode")]
+  pub fn open_options(mut self, options: OpenOptions) -> Self {
+    self.open_options = options;
+    self
+  }
+
+  /// Saves equal data as the same bytes, e.g. for files kept in git or compared by hash.
+  ///
+  /// Map entries are sorted and floats normalized, and a save is skipped when the data didn't
+  /// change. The file records its creation time as when it was modified, so that doesn't
+  /// differ between saves. Encryption uses a random nonce, so only unencrypted files are
+  /// byte-identical.
+  #[cfg(feature = "bincode")]
+  pub fn canonical(mut self) -> Self {
+    self.canonical = true;
+    self
+  }
+
+  /// Profiles every save and logs a summary of the last `every` through the `log` crate, see
+  /// [`Database::save_profile`].
+  ///
+  /// Shows whether delta saves, compression or splitting the data would pay off. The previous
+  /// payload is kept to count the bytes that changed, so this doubles the memory used.
+  #[cfg(feature = "bincode")]
+  pub fn profile_saves(mut self, every: Duration) -> Self {
+    self.profile = Some(every);
+    self
+  }
+
+  /// Decides what opening does when the file is empty or was cut short, instead of failing with
+  /// [`DataError::Truncated`].
+  #[cfg(feature = "bincode")]
+  pub fn on_truncated(mut self, on_truncated: OnTruncated) -> Self {
+    self.on_truncated = on_truncated;
+    self
+  }
+
+  /// Adds the database to the registry behind [`crate::flush_all`] and [`crate::open_databases`],
+  /// so shutdown code can reach it without it being passed around.
+  pub fn register(mut self) -> Self {
+    self.register = true;
+    self
+  }
+
+  /// Uses `clock` instead of the system clock, see [`crate::time`].
+  pub fn clock<C: Clock>(mut self, clock: C) -> Self {
+    self.clock = Arc::new(clock);
+    self
+  }
+
+  /// Runs the saver with `scheduler` instead of on its own thread, see [`crate::time`].
+  pub fn scheduler<S: Scheduler>(mut self, scheduler: S) -> Self {
+    self.saver.scheduler = Some(Arc::new(scheduler));
+    self
+  }
+
+  /// Logs a warning with the caller's location through the `log` crate whenever a
+  /// [`crate::ReadGuard`] or [`crate::WriteGuard`] is held for longer than `threshold`.
+  ///
+  /// Long-held guards block the saver and every other thread, so this helps find the code
+  /// holding them.
+  pub fn warn_held(mut self, threshold: Duration) -> Self {
+    self.warn_held = Some(threshold);
+    self
+  }
+
+  /// Injects faults into writes to the file, see [`crate::testing`].
+  #[cfg(feature = "testing")]
+  pub fn faults(mut self, faults: FaultInjector) -> Self {
+    self.faults = Some(faults);
+    self
+  }
+
+  /// Encodes `data` exactly like it would be saved to a file, with a new [`Metadata`].
+  #[cfg(feature = "bincode")]
+  pub fn to_bytes(&self, data: &T) -> Result<Vec<u8>, DataError>
+  where
+    T: Serialize,
+  {
+    let mut format = self.format.clone();
+    format.init(None)?;
+    let mut metadata = Metadata::new();
+    metadata.app_version = self.app_version.clone();
+    let payload = match self.canonical {
+      true => canonical::serialize(data)?,
+      false => bincode::serialize(data)?,
+    };
+    format.encode(payload, &metadata)
+  }
+
+  /// Decodes bytes from [`Builder::to_bytes`] or [`Database::to_bytes`], or the contents of a file.
+  ///
+  /// The metadata is `None` for data written before metadata was stored.
+  #[cfg(feature = "bincode")]
+  pub fn from_bytes(&self, bytes: &[u8]) -> Result<(T, Option<Metadata>), DataError>
+  where
+    T: DeserializeOwned,
+  {
+    let (payload, metadata) = self.format.clone().decode(bytes)?;
+    Ok((bincode::deserialize(&payload)?, metadata))
+  }
+
+  /// Opens a bincode database at `path`, starting from `T::default()` if it doesn't exist.
+  #[cfg(feature = "bincode")]
+  pub fn open<P: AsRef<Path>>(mut self, path: P) -> Result<Database<T>, DataError>
+  where
+    T: Serialize + DeserializeOwned + Default,
+  {
+    let (disk, data, metadata) = self.open_disk(path.as_ref().to_path_buf())?;
+    Database::spawn(
+      data,
+      metadata,
+      move |data, metadata| disk.save(data, metadata).map(Some),
+      self,
+    )
+  }
+
+  /// Like [`Builder::open`], but the data is deserialized with `seed`, for data that needs context
+  /// to be deserialized such as an interner or a map of ids.
+  ///
+  /// `default` creates the data when the file doesn't exist.
+  #[cfg(feature = "bincode")]
+  pub fn open_seed<P, S, D>(
+    mut self,
+    path: P,
+    seed: S,
+    default: D,
+  ) -> Result<Database<T>, DataError>
+  where
+    P: AsRef<Path>,
+    T: Serialize,
+    S: for<'de> DeserializeSeed<'de, Value = T>,
+    D: FnOnce() -> T,
+  {
+    // a truncated file can only be recovered from before the seed was used
+    let (mut seed, mut default) = (Some(seed), Some(default));
+    let (disk, data, metadata) = self.open_disk_with(path.as_ref().to_path_buf(), |payload| {
+      Ok(match payload {
+        // the same options as bincode::deserialize
+        Some(payload) => bincode::DefaultOptions::new()
+          .with_fixint_encoding()
+          .allow_trailing_bytes()
+          .deserialize_seed(seed.take().ok_or(DataError::Truncated)?, payload)?,
+        None => default.take().ok_or(DataError::Truncated)?(),
+      })
+    })?;
+    Database::spawn(
+      data,
+      metadata,
+      move |data, metadata| disk.save(data, metadata).map(Some),
+      self,
+    )
+  }
+
+  /// Like [`Builder::open`], but the file is loaded from `reader` and each save writes all of it
+  /// to a new writer from `writer`, e.g. a socket, a pipe or an in-memory buffer.
+  ///
+  /// An empty reader starts from `T::default()`. The options for files, like delta saves, mmap,
+  /// open options, truncation handling and faults, as well as canonical and profiled saves,
+  /// aren't supported and fail with [`DataError::Unsupported`].
+  #[cfg(feature = "bincode")]
+  pub fn open_with<R, W, F>(mut self, reader: R, writer: F) -> Result<Database<T>, DataError>
+  where
+    R: Read,
+    W: Write + 'static,
+    T: Serialize + DeserializeOwned + Default,
+    F: Fn() -> io::Result<W> + Send + Sync + 'static,
+  {
+    let unsupported = [
+      ("delta_saves", self.snapshot_every.is_some()),
+      #[cfg(feature = "mmap")]
+      ("mmap", self.mmap),
+      ("open_options", self.open_options != OpenOptions::default()),
+      ("canonical", self.canonical),
+      ("profile_saves", self.profile.is_some()),
+      (
+        "on_truncated",
+        !matches!(self.on_truncated, OnTruncated::Fail),
+      ),
+      #[cfg(feature = "testing")]
+      ("faults", self.faults.is_some()),
+    ];
+    if let Some((option, _)) = unsupported.into_iter().find(|(_, set)| *set) {
+      return Err(DataError::Unsupported(option));
+    }
+    let format = mem::take(&mut self.format);
+    let writer = Box::new(move || writer().map(|w| Box::new(w) as Box<dyn Write>));
+    let (disk, data, metadata) = Disk::read(reader, format, writer)?;
+    let disk = Arc::new(disk);
+    self.disk = Some(disk.clone());
+    Database::spawn(
+      data,
+      metadata.unwrap_or_else(Metadata::new),
+      move |data, metadata| disk.save(data, metadata).map(Some),
+      self,
+    )
+  }
+
+  /// Like [`Builder::open`], but the file is read and written on tokio's blocking threads.
+  ///
+  /// The data is serialized on the runtime by a task that follows the [`SavePolicy`], instead of
+  /// a saver thread. Use [`Database::saved`] to wait for a write to be persisted.
+  #[cfg(feature = "tokio")]
+  pub async fn open_async<P: AsRef<Path>>(self, path: P) -> Result<Database<T>, DataError>
+  where
+    T: Serialize + DeserializeOwned + Default,
+  {
+    let (mut builder, disk, data, metadata) = self.open_disk_blocking(path.as_ref()).await?;
+    let options = mem::take(&mut builder.saver);
+    let save = Box::new(move |data: &T, metadata: &Metadata| disk.save(data, metadata).map(Some));
+    let db = Database::create(data, metadata, save, builder)?;
+    if options.policy != SavePolicy::Manual {
+      db.spawn_task(options);
+    }
+    Ok(db)
+  }
+
+  /// Opens a database owned by a task on the current runtime instead of behind a lock.
+  ///
+  /// The file is read and written on tokio's blocking threads like with [`Builder::open_async`].
+  /// There is no saver thread or lock, so the options for them, the audit log and the registry
+  /// aren't supported and fail with [`DataError::Unsupported`].
+  #[cfg(feature = "tokio")]
+  pub async fn open_actor<P: AsRef<Path>>(self, path: P) -> Result<DatabaseActor<T>, DataError>
+  where
+    T: Serialize + DeserializeOwned + Default,
+  {
+    #[cfg(feature = "audit")]
+    if self.audit_log.is_some() {
+      return Err(DataError::Unsupported("audit_log"));
+    }
+    let unsupported = [
+      ("save_policy", self.saver.policy != SavePolicy::default()),
+      ("saver_name", self.saver.name.is_some()),
+      ("saver_priority", self.saver.priority.is_some()),
+      ("on_saver_panic", self.saver.on_panic.is_some()),
+      ("scheduler", self.saver.scheduler.is_some()),
+      ("on_dead_saver", self.on_dead_saver.is_some()),
+      ("warn_held", self.warn_held.is_some()),
+      ("register", self.register),
+    ];
+    if let Some((option, _)) = unsupported.into_iter().find(|(_, set)| *set) {
+      return Err(DataError::Unsupported(option));
+    }
+    let (builder, disk, data, metadata) = self.open_disk_blocking(path.as_ref()).await?;
+    builder.check_loaded(&metadata)?;
+    let mut actor = Actor::new(data, metadata, disk, builder.clock);
+    actor.app_version = builder.app_version;
+    actor.on_saved = builder.on_saved;
+    Ok(DatabaseActor::spawn(actor))
+  }
+
+  #[cfg(feature = "tokio")]
+  async fn open_disk_blocking(
+    mut self,
+    path: &Path,
+  ) -> Result<(Self, Arc<Disk>, T, Metadata), DataError>
+  where
+    T: DeserializeOwned + Default,
+  {
+    let path = path.to_path_buf();
+    let (builder, opened) = tokio::task::spawn_blocking(move || {
+      let opened = self.open_disk(path);
+      (self, opened)
+    })
+    .await
+    .unwrap();
+    let (disk, data, metadata) = opened?;
+    Ok((builder, disk, data, metadata))
+  }
+
+  #[cfg(feature = "bincode")]
+  fn open_disk(&mut self, path: PathBuf) -> Result<(Arc<Disk>, T, Metadata), DataError>
+  where
+    T: DeserializeOwned + Default,
+  {
+    self.open_disk_with(path, |payload| {
+      Ok(match payload {
+        Some(payload) => bincode::deserialize(payload)?,
+        None => T::default(),
+      })
+    })
+  }
+
+  /// Opens a database whose payload is encoded with `encode` and decoded with `decode` instead
+  /// of bincode, starting from `T::default()` if the file doesn't exist.
+  ///
+  /// With `raw` the file is only the payload, so setting the format options or delta saves fails
+  /// with [`DataError::Unsupported`] instead of silently dropping them.
+  #[cfg(any(feature = "prost", feature = "borsh", feature = "yaml"))]
+  pub(crate) fn open_encoded<D, E>(
+    mut self,
+    path: &Path,
+    raw: bool,
+    decode: D,
+    encode: E,
+  ) -> Result<Database<T>, DataError>
+  where
+    T: Default,
+    D: Fn(&[u8]) -> Result<T, DataError>,
+    E: Fn(&T) -> Result<Vec<u8>, DataError> + Send + Sync + 'static,
+  {
+    if raw {
+      let unsupported = [
+        ("compression", self.format.compression != Compression::None),
+        #[cfg(feature = "zstd")]
+        ("zstd_dictionary", self.format.dictionary.is_some()),
+        #[cfg(feature = "encryption")]
+        ("key", self.format.secret.is_some()),
+        #[cfg(feature = "signing")]
+        ("sign", self.format.signing.is_some()),
+        ("delta_saves", self.snapshot_every.is_some()),
+      ];
+      if let Some((option, _)) = unsupported.into_iter().find(|(_, set)| *set) {
+        return Err(DataError::Unsupported(option));
+      }
+      self.format.raw = true;
+    }
+    let (disk, data, metadata) = self.open_disk_with(path.to_path_buf(), |payload| {
+      Ok(payload.map(&decode).transpose()?.unwrap_or_default())
+    })?;
+    let encode: Encode<T> = Arc::new(encode);
+    self.encode = Some(encode.clone());
+    Database::spawn(
+      data,
+      metadata,
+      move |data, metadata| disk.save_payload(encode(data)?, metadata).map(Some),
+      self,
+    )
+  }
+
+  #[cfg(feature = "bincode")]
+  fn open_disk_with<L: FnMut(Option<&[u8]>) -> Result<T, DataError>>(
+    &mut self,
+    path: PathBuf,
+    load: L,
+  ) -> Result<(Arc<Disk>, T, Metadata), DataError> {
+    let format = mem::take(&mut self.format);
+    #[cfg(feature = "mmap")]
+    let mmap = self.mmap;
+    #[cfg(not(feature = "mmap"))]
+    let mmap = false;
+    let (mut disk, data, metadata) = Disk::open_with(
+      path,
+      format,
+      self.snapshot_every,
+      mmap,
+      self.canonical,
+      &self.on_truncated,
+      load,
+    )?;
+    disk.options = self.open_options;
+    disk.profiler = self.profile.map(Profiler::new);
+    #[cfg(feature = "testing")]
+    {
+      disk.faults = self.faults.clone();
+    }
+    let disk = Arc::new(disk);
+    self.disk = Some(disk.clone());
+    Ok((disk, data, metadata.unwrap_or_else(Metadata::new)))
+  }
+
+  /// Creates a database from `data`, persisted by calling `save` whenever it is dirty.
+  pub fn build<S: Fn(&T) + Send + 'static>(
+    self,
+    data: T,
+    save: S,
+  ) -> Result<Database<T>, DataError> {
+    Database::spawn(
+      data,
+      Metadata::new(),
+      move |data, _| {
+        save(data);
+        Ok(None)
+      },
+      self,
+    )
+  }
+}
+
+impl<T: Send + Sync + 'static> Default for Builder<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+#[cfg(all(test, feature = "bincode"))]
+mod test {
+  use std::sync::Mutex;
+  use super::*;
+
+  struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+  impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+      self.0.lock().unwrap().extend_from_slice(buf);
+      Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test() {
+    let saved = Arc::new(Mutex::new(vec![]));
+    let s = saved.clone();
+    let db = Database::<Vec<u32>>::builder()
+      .open_with(io::empty(), move || {
+        s.lock().unwrap().clear();
+        Ok(Buffer(s.clone()))
+      })
+      .unwrap();
+    db.get_mut().push(1);
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    let bytes = loop {
+      let bytes = saved.lock().unwrap().clone();
+      if !bytes.is_empty() {
+        break bytes;
+      }
+      assert!(std::time::Instant::now() < deadline, "never saved");
+      std::thread::sleep(Duration::from_millis(1));
+    };
+    let db = Database::<Vec<u32>>::builder()
+      .open_with(&bytes[..], || Ok(io::sink()))
+      .unwrap();
+    assert_eq!(db.get()[..], [1]);
+    let db = Database::<Vec<u32>>::builder()
+      .delta_saves(10)
+      .open_with(io::empty(), || Ok(io::sink()));
+    assert!(matches!(db, Err(DataError::Unsupported("delta_saves"))));
+  }
+
+  #[test]
+  fn bytes() {
+    let builder = Database::<String>::builder().app_version("1.0");
+    let bytes = builder.to_bytes(&"floppa".to_string()).unwrap();
+    let (data, metadata) = builder.from_bytes(&bytes).unwrap();
+    assert_eq!(data, "floppa");
+    assert_eq!(metadata.unwrap().app_version.as_deref(), Some("1.0"));
+
+    let db = Database::new_custom(data, |_| {});
+    assert_eq!(
+      builder.from_bytes(&db.to_bytes().unwrap()).unwrap().0,
+      "floppa"
+    );
+  }
+
+  /// Deserializes a number that was saved halved.
+  struct Doubled;
+
+  impl<'de> DeserializeSeed<'de> for Doubled {
+    type Value = u32;
+
+    fn deserialize<D: serde::Deserializer<'de>>(self, d: D) -> Result<u32, D::Error> {
+      Ok(<u32 as serde::Deserialize>::deserialize(d)? * 2)
+    }
+  }
+
+  #[test]
+  fn seed() {
+    let path = std::env::temp_dir().join("floppadb-seed.db");
+    let _ = std::fs::remove_file(&path);
+    let db = Database::<u32>::builder()
+      .manual_save()
+      .open_seed(&path, Doubled, || 1)
+      .unwrap();
+    assert_eq!(*db.get(), 1);
+    *db.get_mut() = 5;
+    db.flush().unwrap();
+    let db = Database::<u32>::builder()
+      .open_seed(&path, Doubled, || 1)
+      .unwrap();
+    assert_eq!(*db.get(), 10);
+  }
+}
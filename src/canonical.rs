@@ -0,0 +1,345 @@
+//! Serializes to the same bytes as `bincode::serialize`, except that map entries are sorted, see
+//! [`crate::Builder::canonical`].
+
+use serde::ser::{self, Serialize};
+
+type Error = bincode::Error;
+
+/// Serializes `data` so that equal data always gives the same bytes.
+///
+/// Map entries are sorted by their serialized key, `-0.0` is written as `0.0` and every NaN as
+/// the same NaN. Sets are sequences to serde, so their order can't be fixed.
+pub(crate) fn serialize<T: Serialize + ?Sized>(data: &T) -> Result<Vec<u8>, Error> {
+  let mut out = vec![];
+  data.serialize(Canonical(&mut out))?;
+  Ok(out)
+}
+
+struct Canonical<'a>(&'a mut Vec<u8>);
+
+/// A sequence or map whose length is written once it is known.
+struct Seq<'a> {
+  out: &'a mut Vec<u8>,
+  start: usize,
+  len: u64,
+}
+
+impl<'a> Seq<'a> {
+  fn new(out: &'a mut Vec<u8>) -> Self {
+    let start = out.len();
+    out.extend(0u64.to_le_bytes());
+    Self { out, start, len: 0 }
+  }
+
+  fn end(self) {
+    self.out[self.start..self.start + 8].copy_from_slice(&self.len.to_le_bytes());
+  }
+}
+
+struct Map<'a> {
+  out: &'a mut Vec<u8>,
+  entries: Vec<(Vec<u8>, Vec<u8>)>,
+  key: Vec<u8>,
+}
+
+macro_rules! int {
+  ($($f:ident $t:ty),*) => {
+    $(fn $f(self, v: $t) -> Result<(), Error> {
+      self.0.extend(v.to_le_bytes());
+      Ok(())
+    })*
+  };
+}
+
+impl<'a> ser::Serializer for Canonical<'a> {
+  type Ok = ();
+  type Error = Error;
+  type SerializeSeq = Seq<'a>;
+  type SerializeTuple = Self;
+  type SerializeTupleStruct = Self;
+  type SerializeTupleVariant = Self;
+  type SerializeMap = Map<'a>;
+  type SerializeStruct = Self;
+  type SerializeStructVariant = Self;
+
+  int!(
+    serialize_i8 i8, serialize_i16 i16, serialize_i32 i32, serialize_i64 i64, serialize_i128 i128,
+    serialize_u8 u8, serialize_u16 u16, serialize_u32 u32, serialize_u64 u64, serialize_u128 u128
+  );
+
+  fn serialize_bool(self, v: bool) -> Result<(), Error> {
+    self.serialize_u8(v as u8)
+  }
+
+  fn serialize_f32(self, v: f32) -> Result<(), Error> {
+    let v = if v.is_nan() { f32::NAN } else { v + 0.0 };
+    self.serialize_u32(v.to_bits())
+  }
+
+  fn serialize_f64(self, v: f64) -> Result<(), Error> {
+    let v = if v.is_nan() { f64::NAN } else { v + 0.0 };
+    self.serialize_u64(v.to_bits())
+  }
+
+  fn serialize_char(self, v: char) -> Result<(), Error> {
+    self.0.extend(v.encode_utf8(&mut [0; 4]).as_bytes());
+    Ok(())
+  }
+
+  fn serialize_str(self, v: &str) -> Result<(), Error> {
+    self.serialize_bytes(v.as_bytes())
+  }
+
+  fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+    self.0.extend((v.len() as u64).to_le_bytes());
+    self.0.extend(v);
+    Ok(())
+  }
+
+  fn serialize_none(self) -> Result<(), Error> {
+    self.serialize_u8(0)
+  }
+
+  fn serialize_some<T: Serialize + ?Sized>(self, v: &T) -> Result<(), Error> {
+    self.0.push(1);
+    v.serialize(self)
+  }
+
+  fn serialize_unit(self) -> Result<(), Error> {
+    Ok(())
+  }
+
+  fn serialize_unit_struct(self, _: &'static str) -> Result<(), Error> {
+    Ok(())
+  }
+
+  fn serialize_unit_variant(self, _: &'static str, i: u32, _: &'static str) -> Result<(), Error> {
+    self.serialize_u32(i)
+  }
+
+  fn serialize_newtype_struct<T: Serialize + ?Sized>(
+    self,
+    _: &'static str,
+    v: &T,
+  ) -> Result<(), Error> {
+    v.serialize(self)
+  }
+
+  fn serialize_newtype_variant<T: Serialize + ?Sized>(
+    self,
+    _: &'static str,
+    i: u32,
+    _: &'static str,
+    v: &T,
+  ) -> Result<(), Error> {
+    self.0.extend(i.to_le_bytes());
+    v.serialize(self)
+  }
+
+  fn serialize_seq(self, _: Option<usize>) -> Result<Seq<'a>, Error> {
+    Ok(Seq::new(self.0))
+  }
+
+  fn serialize_tuple(self, _: usize) -> Result<Self, Error> {
+    Ok(self)
+  }
+
+  fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+    Ok(self)
+  }
+
+  fn serialize_tuple_variant(
+    self,
+    _: &'static str,
+    i: u32,
+    _: &'static str,
+    _: usize,
+  ) -> Result<Self, Error> {
+    self.0.extend(i.to_le_bytes());
+    Ok(self)
+  }
+
+  fn serialize_map(self, _: Option<usize>) -> Result<Map<'a>, Error> {
+    Ok(Map {
+      out: self.0,
+      entries: vec![],
+      key: vec![],
+    })
+  }
+
+  fn serialize_struct(self, _: &'static str, _: usize) -> Result<Self, Error> {
+    Ok(self)
+  }
+
+  fn serialize_struct_variant(
+    self,
+    _: &'static str,
+    i: u32,
+    _: &'static str,
+    _: usize,
+  ) -> Result<Self, Error> {
+    self.0.extend(i.to_le_bytes());
+    Ok(self)
+  }
+
+  fn is_human_readable(&self) -> bool {
+    false
+  }
+}
+
+impl ser::SerializeSeq for Seq<'_> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), Error> {
+    self.len += 1;
+    v.serialize(Canonical(self.out))
+  }
+
+  fn end(self) -> Result<(), Error> {
+    Seq::end(self);
+    Ok(())
+  }
+}
+
+impl ser::SerializeMap for Map<'_> {
+  type Ok = ();
+  type Error = Error;
+
+  fn serialize_key<T: Serialize + ?Sized>(&mut self, k: &T) -> Result<(), Error> {
+    self.key = serialize(k)?;
+    Ok(())
+  }
+
+  fn serialize_value<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), Error> {
+    self
+      .entries
+      .push((std::mem::take(&mut self.key), serialize(v)?));
+    Ok(())
+  }
+
+  fn end(mut self) -> Result<(), Error> {
+    self.entries.sort_unstable();
+    self.out.extend((self.entries.len() as u64).to_le_bytes());
+    for (k, v) in self.entries {
+      self.out.extend(k);
+      self.out.extend(v);
+    }
+    Ok(())
+  }
+}
+
+macro_rules! compound {
+  ($($t:ident $f:ident($($name:ty)?)),*) => {
+    $(impl ser::$t for Canonical<'_> {
+      type Ok = ();
+      type Error = Error;
+
+      fn $f<T: Serialize + ?Sized>(&mut self, $(_: $name,)? v: &T) -> Result<(), Error> {
+        v.serialize(Canonical(self.0))
+      }
+
+      fn end(self) -> Result<(), Error> {
+        Ok(())
+      }
+    })*
+  };
+}
+
+compound!(
+  SerializeTuple serialize_element(),
+  SerializeTupleStruct serialize_field(),
+  SerializeTupleVariant serialize_field(),
+  SerializeStruct serialize_field(&'static str),
+  SerializeStructVariant serialize_field(&'static str)
+);
+
+#[cfg(test)]
+mod test {
+  use std::collections::{BTreeMap, HashMap};
+  use serde::{Serialize, Deserialize};
+  use super::*;
+
+  #[derive(Serialize, Deserialize, PartialEq, Debug)]
+  enum Shape {
+    Point,
+    Circle(f64),
+    Rect { w: u32, h: u32 },
+  }
+
+  #[derive(Serialize, Deserialize, PartialEq, Debug)]
+  struct Test {
+    name: String,
+    c: char,
+    shapes: Vec<Option<Shape>>,
+    pair: (i8, u128),
+    sorted: BTreeMap<u16, bool>,
+    map: HashMap<String, u32>,
+  }
+
+  fn test_data(keys: impl Iterator<Item = u32>) -> Test {
+    Test {
+      name: "floppa".to_string(),
+      c: 'ß',
+      shapes: vec![
+        None,
+        Some(Shape::Point),
+        Some(Shape::Circle(-0.0)),
+        Some(Shape::Rect { w: 1, h: 2 }),
+      ],
+      pair: (-1, u128::MAX),
+      sorted: BTreeMap::from([(1, true), (2, false)]),
+      map: keys.map(|k| (k.to_string(), k)).collect(),
+    }
+  }
+
+  #[test]
+  fn test() {
+    let a = test_data(0..100);
+    let b = test_data((0..100).rev());
+    let bytes = serialize(&a).unwrap();
+    assert_eq!(bytes, serialize(&b).unwrap());
+    assert_eq!(bincode::deserialize::<Test>(&bytes).unwrap(), a);
+
+    let mut a = a;
+    a.map.clear();
+    a.shapes[2] = Some(Shape::Circle(0.0));
+    assert_eq!(serialize(&a).unwrap(), bincode::serialize(&a).unwrap());
+  }
+
+  #[test]
+  fn database() {
+    let path = std::env::temp_dir().join("floppadb-canonical.db");
+    let _ = std::fs::remove_file(&path);
+    let db = crate::Database::<HashMap<String, u32>>::builder()
+      .manual_save()
+      .canonical()
+      .open(&path)
+      .unwrap();
+    db.get_mut().extend((0..100).map(|k| (k.to_string(), k)));
+    db.flush().unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    *db.get_mut() = (0..100).rev().map(|k| (k.to_string(), k)).collect();
+    db.flush().unwrap();
+    assert!(std::fs::read(&path).unwrap() == bytes);
+    // when it was saved isn't part of the file
+    db.get_mut().insert("floppa".to_string(), 1);
+    db.flush().unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    db.get_mut().remove("floppa");
+    db.flush().unwrap();
+    assert!(std::fs::read(&path).unwrap() == bytes);
+
+    drop(db);
+    let (tx, rx) = std::sync::mpsc::channel();
+    let db = crate::Database::<HashMap<String, u32>>::builder()
+      .manual_save()
+      .canonical()
+      .on_saved(move |saved| tx.send(saved.bytes).unwrap())
+      .open(&path)
+      .unwrap();
+    db.get_mut();
+    db.flush().unwrap();
+    assert_eq!(rx.try_recv(), Ok(Some(0)));
+  }
+}
@@ -0,0 +1,500 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::time::{Duration, SystemTime};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use crate::Database;
+
+/// A map whose entries can expire after a ttl.
+///
+/// Expired entries are hidden from lookups straight away, but are only removed once
+/// [`Collection::expire`] runs, usually from the sweeper started by [`Database::on_expire`].
+///
+/// A collection can also be capped with [`Collection::limit`] or [`Collection::limit_bytes`], in
+/// which case inserting a new key into a full collection evicts entries picked by its
+/// [`Eviction`] policy.
+#[derive(Serialize, Deserialize)]
+#[serde(bound(
+  serialize = "K: Serialize + Eq + Hash, V: Serialize",
+  deserialize = "K: Deserialize<'de> + Serialize + Eq + Hash, V: Deserialize<'de> + Serialize"
+))]
+pub struct Collection<K, V> {
+  entries: HashMap<Arc<K>, Entry<V>>,
+  ttl: Option<Duration>,
+  limit: Option<(usize, Eviction)>,
+  /// The most bytes the entries may take serialized, see [`Collection::limit_bytes`].
+  max_bytes: Option<u64>,
+  tick: AtomicU64,
+  #[serde(skip)]
+  index: Mutex<Index<K>>,
+  /// Set once the types are known to serialize, so loading a collection restores it.
+  #[serde(skip, default = "sizer::<K, V>")]
+  sizer: Option<Sizer<K, V>>,
+}
+
+/// Measures the serialized size of an entry.
+type Sizer<K, V> = fn(&K, &V) -> u64;
+
+fn sizer<K: Serialize, V: Serialize>() -> Option<Sizer<K, V>> {
+  #[cfg(feature = "bincode")]
+  return Some(|k, v| bincode::serialized_size(&(k, v)).unwrap_or(0));
+  #[cfg(not(feature = "bincode"))]
+  None
+}
+
+/// Decides which entry is evicted when a limited collection is full.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Eviction {
+  /// Evict the least recently used entry.
+  Lru,
+  /// Evict the least frequently used entry.
+  Lfu,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry<V> {
+  value: V,
+  expires: Option<SystemTime>,
+  used: AtomicU64,
+  /// Tells apart entries used equally often in the [`Index`].
+  #[serde(skip)]
+  id: AtomicU64,
+  /// The serialized size as of the last time it was measured, 0 without a byte limit.
+  #[serde(skip)]
+  size: AtomicU64,
+}
+
+impl<V> Entry<V> {
+  fn expired(&self, now: SystemTime) -> bool {
+    self.expires.is_some_and(|e| e <= now)
+  }
+}
+
+/// The entries of a limited collection ordered by use and by expiry, so evicting doesn't scan
+/// them. It isn't saved, and is rebuilt when it doesn't cover every entry, e.g. after loading.
+struct Index<K> {
+  used: BTreeMap<(u64, u64), Arc<K>>,
+  expires: BTreeMap<(SystemTime, u64), Arc<K>>,
+  /// The total size of the entries.
+  bytes: u64,
+  /// Entries handed out by [`Collection::get_mut`], whose size may have changed since.
+  stale: Vec<Arc<K>>,
+}
+
+impl<K> Default for Index<K> {
+  fn default() -> Self {
+    Self {
+      used: BTreeMap::new(),
+      expires: BTreeMap::new(),
+      bytes: 0,
+      stale: vec![],
+    }
+  }
+}
+
+impl<K: Eq + Hash> Index<K> {
+  fn lock<'a, V>(
+    index: &'a Mutex<Self>,
+    entries: &HashMap<Arc<K>, Entry<V>>,
+    tick: &AtomicU64,
+    sizer: Option<Sizer<K, V>>,
+  ) -> MutexGuard<'a, Self> {
+    let mut index = index.lock().unwrap();
+    if index.used.len() != entries.len() {
+      *index = Self::default();
+      for (key, entry) in entries {
+        entry
+          .id
+          .store(tick.fetch_add(1, Ordering::Relaxed) + 1, Ordering::Relaxed);
+        if let Some(size) = sizer {
+          entry.size.store(size(key, &entry.value), Ordering::Relaxed);
+        }
+        index.insert(key, entry);
+      }
+    }
+    index
+  }
+
+  fn insert<V>(&mut self, key: &Arc<K>, entry: &Entry<V>) {
+    let id = entry.id.load(Ordering::Relaxed);
+    self
+      .used
+      .insert((entry.used.load(Ordering::Relaxed), id), key.clone());
+    if let Some(expires) = entry.expires {
+      self.expires.insert((expires, id), key.clone());
+    }
+    self.bytes += entry.size.load(Ordering::Relaxed);
+  }
+
+  fn remove<V>(&mut self, entry: &Entry<V>) {
+    let id = entry.id.load(Ordering::Relaxed);
+    self.used.remove(&(entry.used.load(Ordering::Relaxed), id));
+    if let Some(expires) = entry.expires {
+      self.expires.remove(&(expires, id));
+    }
+    self.bytes -= entry.size.load(Ordering::Relaxed);
+  }
+
+  /// Measures the entries changed through [`Collection::get_mut`] again.
+  fn measure<V>(&mut self, entries: &HashMap<Arc<K>, Entry<V>>, sizer: Option<Sizer<K, V>>) {
+    for key in std::mem::take(&mut self.stale) {
+      if let (Some(size), Some(entry)) = (sizer, entries.get(&key)) {
+        let new = size(&key, &entry.value);
+        self.bytes = self.bytes - entry.size.swap(new, Ordering::Relaxed) + new;
+      }
+    }
+  }
+}
+
+impl<K: Eq + Hash, V> Collection<K, V> {
+  pub fn new() -> Self {
+    Self {
+      entries: HashMap::new(),
+      ttl: None,
+      limit: None,
+      max_bytes: None,
+      tick: AtomicU64::new(0),
+      index: Mutex::default(),
+      sizer: None,
+    }
+  }
+
+  /// Creates a collection where every entry inserted with [`Collection::insert`] expires after `ttl`.
+  pub fn with_ttl(ttl: Duration) -> Self {
+    Self {
+      ttl: Some(ttl),
+      ..Self::new()
+    }
+  }
+
+  /// Caps the collection at `capacity` entries.
+  ///
+  /// Expired entries are always evicted first. The entries are kept ordered by use, so using and
+  /// evicting an entry takes `O(log n)`.
+  pub fn limit(mut self, capacity: usize, eviction: Eviction) -> Self {
+    self.limit = Some((capacity, eviction));
+    self
+  }
+
+  /// The sizer to measure entries with, if there is a byte limit.
+  fn sizer(&self) -> Option<Sizer<K, V>> {
+    self.max_bytes.and(self.sizer)
+  }
+
+  fn touch(&self, entry: &Entry<V>) {
+    let Some((_, eviction)) = self.limit else {
+      return;
+    };
+    let mut index = Index::lock(&self.index, &self.entries, &self.tick, self.sizer());
+    let id = entry.id.load(Ordering::Relaxed);
+    let key = index.used.remove(&(entry.used.load(Ordering::Relaxed), id));
+    let used = match eviction {
+      Eviction::Lfu => entry.used.load(Ordering::Relaxed) + 1,
+      Eviction::Lru => self.tick.fetch_add(1, Ordering::Relaxed) + 1,
+    };
+    entry.used.store(used, Ordering::Relaxed);
+    if let Some(key) = key {
+      index.used.insert((used, id), key);
+    }
+  }
+
+  /// Makes room for an entry of `size` bytes.
+  fn evict(&mut self, now: SystemTime, size: u64) {
+    let Some((capacity, _)) = self.limit else {
+      return;
+    };
+    let sizer = self.sizer();
+    let mut index = Index::lock(&self.index, &self.entries, &self.tick, sizer);
+    index.measure(&self.entries, sizer);
+    let max_bytes = self.max_bytes.unwrap_or(u64::MAX);
+    while self.entries.len() >= capacity.max(1) || index.bytes.saturating_add(size) > max_bytes {
+      let victim = match index.expires.first_key_value() {
+        Some((&(expires, _), _)) if expires <= now => index.expires.pop_first().map(|(_, k)| k),
+        _ => index.used.pop_first().map(|(_, k)| k),
+      };
+      let Some(key) = victim else {
+        break;
+      };
+      if let Some(entry) = self.entries.remove(&*key) {
+        index.remove(&entry);
+      }
+    }
+  }
+
+  /// Removes the entry for `key`, including from the index.
+  fn remove_entry(&mut self, key: &K) -> Option<Entry<V>> {
+    let entry = self.entries.remove(key)?;
+    if self.limit.is_some() {
+      self.index.get_mut().unwrap().remove(&entry);
+    }
+    Some(entry)
+  }
+
+  pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+    self.insert_entry(key, value, self.ttl)
+  }
+
+  pub fn insert_with_ttl(&mut self, key: K, value: V, ttl: Duration) -> Option<V> {
+    self.insert_entry(key, value, Some(ttl))
+  }
+
+  fn insert_entry(&mut self, key: K, value: V, ttl: Option<Duration>) -> Option<V> {
+    let now = SystemTime::now();
+    let old = self.remove_entry(&key);
+    let size = self.sizer().map_or(0, |size| size(&key, &value));
+    if old.is_none() || size > 0 {
+      self.evict(now, size);
+    }
+    let key = Arc::new(key);
+    let entry = Entry {
+      value,
+      expires: ttl.map(|t| now + t),
+      used: AtomicU64::new(0),
+      id: AtomicU64::new(0),
+      size: AtomicU64::new(size),
+    };
+    if let Some((_, eviction)) = self.limit {
+      let mut index = Index::lock(&self.index, &self.entries, &self.tick, self.sizer());
+      let id = self.tick.fetch_add(1, Ordering::Relaxed) + 1;
+      entry.id.store(id, Ordering::Relaxed);
+      let used = match eviction {
+        Eviction::Lfu => 1,
+        Eviction::Lru => id,
+      };
+      entry.used.store(used, Ordering::Relaxed);
+      index.insert(&key, &entry);
+    }
+    self.entries.insert(key, entry);
+    old.filter(|e| !e.expired(now)).map(|e| e.value)
+  }
+
+  pub fn get(&self, key: &K) -> Option<&V> {
+    let now = SystemTime::now();
+    let entry = self.entries.get(key).filter(|e| !e.expired(now))?;
+    self.touch(entry);
+    Some(&entry.value)
+  }
+
+  pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+    let now = SystemTime::now();
+    let (key, entry) = self
+      .entries
+      .get_key_value(key)
+      .filter(|(_, e)| !e.expired(now))?;
+    self.touch(entry);
+    let key = key.clone();
+    if self.sizer().is_some() {
+      // measured again before the next eviction
+      self.index.get_mut().unwrap().stale.push(key.clone());
+    }
+    self.entries.get_mut(&key).map(|e| &mut e.value)
+  }
+
+  pub fn contains_key(&self, key: &K) -> bool {
+    self.get(key).is_some()
+  }
+
+  pub fn remove(&mut self, key: &K) -> Option<V> {
+    let now = SystemTime::now();
+    self
+      .remove_entry(key)
+      .filter(|e| !e.expired(now))
+      .map(|e| e.value)
+  }
+
+  /// Number of entries, including expired entries that have not been swept yet.
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+    let now = SystemTime::now();
+    self
+      .entries
+      .iter()
+      .filter(move |(_, e)| !e.expired(now))
+      .map(|(k, e)| (&**k, &e.value))
+  }
+
+  /// Returns true if any entry has expired and is waiting to be swept.
+  pub fn has_expired(&self) -> bool {
+    self.has_expired_at(SystemTime::now())
+  }
+
+  /// Like [`Collection::has_expired`], at `now` instead of the current time.
+  pub fn has_expired_at(&self, now: SystemTime) -> bool {
+    self.entries.values().any(|e| e.expired(now))
+  }
+
+  /// Removes and returns every expired entry.
+  pub fn expire(&mut self) -> Vec<(K, V)> {
+    self.expire_at(SystemTime::now())
+  }
+
+  /// Like [`Collection::expire`], at `now` instead of the current time.
+  pub fn expire_at(&mut self, now: SystemTime) -> Vec<(K, V)> {
+    let sizer = self.sizer();
+    let index = self.index.get_mut().unwrap();
+    // the stale entries are held by the index too
+    index.measure(&self.entries, sizer);
+    let expired: Vec<_> = self.entries.extract_if(|_, e| e.expired(now)).collect();
+    expired
+      .into_iter()
+      .map(|(k, e)| {
+        index.remove(&e);
+        // the index held the only other reference
+        (Arc::into_inner(k).unwrap(), e.value)
+      })
+      .collect()
+  }
+}
+
+#[cfg(feature = "bincode")]
+impl<K: Serialize + Eq + Hash, V: Serialize> Collection<K, V> {
+  /// Caps the collection at `bytes`, measured as the serialized size of each key and value.
+  ///
+  /// Inserting evicts entries like [`Collection::limit`] until the new one fits, which can also be
+  /// combined with a limit on the number of entries. An entry changed through
+  /// [`Collection::get_mut`] is measured again before the next eviction.
+  pub fn limit_bytes(mut self, bytes: u64, eviction: Eviction) -> Self {
+    let capacity = self.limit.map_or(usize::MAX, |(capacity, _)| capacity);
+    self.limit = Some((capacity, eviction));
+    self.max_bytes = Some(bytes);
+    self.sizer = sizer();
+    self
+  }
+}
+
+impl<K: Eq + Hash, V> Default for Collection<K, V> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl<K, V> Database<Collection<K, V>>
+where
+  K: Serialize + DeserializeOwned + Eq + Hash + Send + Sync + 'static,
+  V: Serialize + DeserializeOwned + Send + Sync + 'static,
+{
+  /// Spawns a sweeper that removes expired entries every `interval`, calling `f` with each one.
+  ///
+  /// The database is only marked dirty when something was actually removed. The sweeper goes by
+  /// the database's [`crate::time::Clock`], and stops once the database is dropped.
+  pub fn on_expire<F: Fn(K, V) + Send + 'static>(&self, interval: Duration, f: F) {
+    let weak = Arc::downgrade(&self.0);
+    let clock = self.0.read().unwrap().clock.clone();
+    thread::spawn(move || loop {
+      clock.sleep(interval);
+      let Some(inner) = weak.upgrade() else {
+        break;
+      };
+      let db = Database(inner);
+      let now = clock.now();
+      if db.get().has_expired_at(now) {
+        let expired = db.get_mut().expire_at(now);
+        for (k, v) in expired {
+          f(k, v);
+        }
+      }
+    });
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::mpsc;
+  use super::*;
+
+  #[test]
+  fn test() {
+    let mut c = Collection::with_ttl(Duration::from_millis(50));
+    c.insert("a".to_string(), 1);
+    c.insert_with_ttl("b".to_string(), 2, Duration::from_secs(60));
+    let db = Database::new_custom(c, |_| {});
+    let (tx, rx) = mpsc::channel();
+    db.on_expire(Duration::from_millis(10), move |k, v| {
+      tx.send((k, v)).unwrap()
+    });
+    assert_eq!(
+      rx.recv_timeout(Duration::from_secs(1)),
+      Ok(("a".to_string(), 1))
+    );
+    assert_eq!(db.get().get(&"a".to_string()), None);
+    assert_eq!(db.get().get(&"b".to_string()), Some(&2));
+
+    let inner = Arc::downgrade(&db.0);
+    drop(db);
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while inner.strong_count() > 0 {
+      assert!(
+        std::time::Instant::now() < deadline,
+        "the sweeper kept the database"
+      );
+      thread::sleep(Duration::from_millis(1));
+    }
+  }
+
+  #[test]
+  fn limit() {
+    let mut c = Collection::new().limit(2, Eviction::Lru);
+    c.insert(1, 1);
+    c.insert(2, 2);
+    c.get(&1);
+    c.insert(3, 3);
+    assert_eq!(c.get(&2), None);
+    assert!(c.contains_key(&1) && c.contains_key(&3));
+
+    let mut c = Collection::new().limit(2, Eviction::Lfu);
+    c.insert(1, 1);
+    c.get(&1);
+    c.insert(2, 2);
+    c.insert(2, 2);
+    c.insert(3, 3);
+    assert_eq!(c.get(&2), None);
+    c.insert_with_ttl(4, 4, Duration::ZERO);
+    c.insert(5, 5);
+    assert_eq!(c.len(), 2);
+    assert!(c.contains_key(&1) && c.contains_key(&5));
+
+    // the index is rebuilt after loading
+    #[cfg(feature = "bincode")]
+    {
+      let bytes = bincode::serialize(&c).unwrap();
+      let mut c: Collection<u32, u32> = bincode::deserialize(&bytes).unwrap();
+      c.insert(6, 6);
+      assert!(c.contains_key(&1) && c.contains_key(&6));
+      assert_eq!(c.expire_at(SystemTime::now()), []);
+    }
+  }
+
+  #[cfg(feature = "bincode")]
+  #[test]
+  fn limit_bytes() {
+    let value = |c: char| c.to_string().repeat(10);
+    let size = bincode::serialized_size(&(1u32, value('a'))).unwrap();
+    let mut c = Collection::new().limit_bytes(size * 2, Eviction::Lru);
+    c.insert(1u32, value('a'));
+    c.insert(2, value('b'));
+    c.insert(3, value('c'));
+    assert_eq!(c.len(), 2);
+    assert!(!c.contains_key(&1));
+
+    // 2 grows to take the room of both, so both go to fit 4
+    c.get_mut(&2).unwrap().push_str(&value('b'));
+    c.insert(4, value('d'));
+    assert_eq!(c.iter().map(|(k, _)| *k).collect::<Vec<_>>(), [4]);
+
+    // the sizes are measured again after loading
+    let bytes = bincode::serialize(&c).unwrap();
+    let mut c: Collection<u32, String> = bincode::deserialize(&bytes).unwrap();
+    c.insert(5, value('e'));
+    c.insert(6, value('f'));
+    assert!(!c.contains_key(&4) && c.contains_key(&5) && c.contains_key(&6));
+  }
+}
@@ -0,0 +1,193 @@
+use std::borrow::Cow;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use std::io::{Read, Write};
+#[cfg(feature = "zstd")]
+use std::path::Path;
+#[cfg(feature = "zstd")]
+use crate::format::Format;
+use crate::DataError;
+
+/// The id stored in the header for zstd with a [`Dictionary`].
+#[cfg(feature = "zstd")]
+pub(crate) const DICTIONARY: u8 = 4;
+
+/// How the payload is compressed, see [`crate::Builder::compression`].
+///
+/// The algorithm is stored in the header so any file can be read back whatever the current
+/// setting is, and changing it re-encodes the file on its next save.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Compression {
+  #[default]
+  None,
+  /// Zstandard with a level from 1 to 22.
+  #[cfg(feature = "zstd")]
+  Zstd(i32),
+  #[cfg(feature = "lz4")]
+  Lz4,
+  /// Gzip with a level from 0 to 9.
+  #[cfg(feature = "gzip")]
+  Gzip(u32),
+}
+
+impl Compression {
+  /// The id stored in the header, which stays the same whichever features are enabled.
+  pub(crate) fn id(self) -> u8 {
+    match self {
+      Self::None => 0,
+      #[cfg(feature = "zstd")]
+      Self::Zstd(_) => 1,
+      #[cfg(feature = "lz4")]
+      Self::Lz4 => 2,
+      #[cfg(feature = "gzip")]
+      Self::Gzip(_) => 3,
+    }
+  }
+
+  pub(crate) fn compress(self, bytes: Vec<u8>) -> Result<Vec<u8>, DataError> {
+    Ok(match self {
+      Self::None => bytes,
+      #[cfg(feature = "zstd")]
+      Self::Zstd(level) => zstd::encode_all(&bytes[..], level)?,
+      #[cfg(feature = "lz4")]
+      Self::Lz4 => lz4_flex::compress_prepend_size(&bytes),
+      #[cfg(feature = "gzip")]
+      Self::Gzip(level) => {
+        let mut e = flate2::write::GzEncoder::new(vec![], flate2::Compression::new(level));
+        e.write_all(&bytes)?;
+        e.finish()?
+      }
+    })
+  }
+}
+
+/// A zstd dictionary trained on similar files, see [`crate::Builder::zstd_dictionary`].
+///
+/// Small files compress poorly on their own, since there is little in each one to refer back to.
+/// A dictionary of what they have in common makes up for that, which suits many small files of
+/// the same type like those of a [`crate::DatabaseSet`].
+#[cfg(feature = "zstd")]
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Dictionary(Vec<u8>);
+
+#[cfg(feature = "zstd")]
+impl Dictionary {
+  /// Uses a dictionary trained earlier, see [`Dictionary::as_bytes`].
+  pub fn new(bytes: Vec<u8>) -> Self {
+    Self(bytes)
+  }
+
+  /// Trains a dictionary of up to `max_size` bytes on the payloads of the database files at
+  /// `paths`.
+  ///
+  /// The files can't be encrypted, and zstd needs a fair number of them to find anything.
+  pub fn train<P: AsRef<Path>, I: IntoIterator<Item = P>>(
+    paths: I,
+    max_size: usize,
+  ) -> Result<Self, DataError> {
+    let mut samples = vec![];
+    for path in paths {
+      let bytes = std::fs::read(path)?;
+      samples.push(Format::default().decode(&bytes)?.0.into_owned());
+    }
+    Ok(Self(zstd::dict::from_samples(&samples, max_size)?))
+  }
+
+  /// The dictionary to store, since files compressed with it can't be read without it.
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.0
+  }
+
+  pub(crate) fn compress(&self, level: i32, bytes: &[u8]) -> Result<Vec<u8>, DataError> {
+    let mut e = zstd::Encoder::with_dictionary(vec![], level, &self.0)?;
+    e.write_all(bytes)?;
+    Ok(e.finish()?)
+  }
+
+  pub(crate) fn decompress(&self, bytes: &[u8]) -> Result<Vec<u8>, DataError> {
+    let mut out = vec![];
+    zstd::Decoder::with_dictionary(bytes, &self.0)?.read_to_end(&mut out)?;
+    Ok(out)
+  }
+}
+
+/// Decompresses `bytes` that were compressed with the algorithm `id`.
+pub(crate) fn decompress(id: u8, bytes: &[u8]) -> Result<Cow<'_, [u8]>, DataError> {
+  match id {
+    0 => Ok(Cow::Borrowed(bytes)),
+    #[cfg(feature = "zstd")]
+    1 => Ok(Cow::Owned(zstd::decode_all(bytes)?)),
+    #[cfg(feature = "lz4")]
+    2 => Ok(Cow::Owned(
+      lz4_flex::decompress_size_prepended(bytes)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?,
+    )),
+    #[cfg(feature = "gzip")]
+    3 => {
+      let mut out = vec![];
+      flate2::read::GzDecoder::new(bytes).read_to_end(&mut out)?;
+      Ok(Cow::Owned(out))
+    }
+    id => Err(DataError::Compression(id)),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let bytes = vec![7; 1000];
+    let all = [
+      Compression::None,
+      #[cfg(feature = "zstd")]
+      Compression::Zstd(3),
+      #[cfg(feature = "lz4")]
+      Compression::Lz4,
+      #[cfg(feature = "gzip")]
+      Compression::Gzip(6),
+    ];
+    for c in all {
+      let compressed = c.compress(bytes.clone()).unwrap();
+      assert_eq!(decompress(c.id(), &compressed).unwrap(), &bytes[..]);
+    }
+  }
+
+  #[cfg(feature = "zstd")]
+  #[test]
+  fn dictionary() {
+    use crate::{Builder, DatabaseSet};
+
+    let dir = std::env::temp_dir().join("floppadb-dictionary");
+    let _ = std::fs::remove_dir_all(&dir);
+    let set = DatabaseSet::<Vec<String>>::new(&dir, 8);
+    for i in 0..200 {
+      set.get(&i.to_string()).unwrap().get_mut().extend([
+        format!("user-{i}@floppa.example"),
+        "theme=dark;locale=en-GB;notifications=weekly".to_string(),
+      ]);
+    }
+    set.close_all().unwrap();
+    let dict = set.train_dictionary(1024).unwrap();
+
+    let path = dir.join("0.db");
+    let open = |dict: Option<&Dictionary>| {
+      let builder = Builder::<Vec<String>>::new()
+        .manual_save()
+        .compression(Compression::Zstd(3));
+      match dict {
+        Some(dict) => builder.zstd_dictionary(dict.clone()),
+        None => builder,
+      }
+      .open(&path)
+    };
+    let plain = open(None).unwrap().to_bytes().unwrap();
+    let db = open(Some(&dict)).unwrap();
+    db.get_mut().push("floppa".to_string());
+    db.close().unwrap();
+    assert!(std::fs::metadata(&path).unwrap().len() < plain.len() as u64);
+    assert!(matches!(open(None), Err(DataError::Dictionary)));
+    let dict = Dictionary::new(dict.as_bytes().to_vec());
+    assert_eq!(open(Some(&dict)).unwrap().get()[2], "floppa");
+  }
+}
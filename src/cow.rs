@@ -0,0 +1,99 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::Database;
+
+/// Storing the data behind an `Arc` lets reads hand out snapshots instead of guards.
+///
+/// Taking a snapshot only holds the lock long enough to bump the refcount, and the snapshot never
+/// blocks writers. A write copies the data only if a snapshot of it is still alive, so this suits
+/// small data that is read far more often than it is written.
+impl<T> Database<Arc<T>>
+where
+  T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static,
+{
+  /// The data as of now, unaffected by later writes.
+  pub fn snapshot(&self) -> Arc<T> {
+    self.with_read(Arc::clone)
+  }
+
+  /// Calls `f` with the data locked for writing, copying it first if a snapshot is still alive.
+  pub fn update<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+    self.with_write(|t| f(Arc::make_mut(t)))
+  }
+}
+
+impl<T: Clone + Send + Sync + 'static> Database<T> {
+  /// Calls `f` with the data locked for writing, rolling its changes back if it fails.
+  ///
+  /// The data is cloned before `f` runs, which is cheap for `Arc` data since `Arc::make_mut` then
+  /// copies it on the first change. A failed update isn't committed, so nothing is saved, counted
+  /// as a version or sent to watchers. A panic in `f` is rolled back the same way before it is
+  /// resumed, so it doesn't poison the database either.
+  #[track_caller]
+  pub fn try_update<R, E, F: FnOnce(&mut T) -> Result<R, E>>(&self, f: F) -> Result<R, E> {
+    let mut guard = self.write(None);
+    let before = guard.clone();
+    // only committed once `f` succeeded
+    guard.4 = false;
+    match panic::catch_unwind(AssertUnwindSafe(|| f(&mut guard))) {
+      Ok(Ok(r)) => {
+        guard.4 = true;
+        Ok(r)
+      }
+      Ok(Err(e)) => {
+        *guard = before;
+        Err(e)
+      }
+      Err(e) => {
+        *guard = before;
+        drop(guard);
+        panic::resume_unwind(e)
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let db = Database::new_custom(Arc::new(vec![1]), |_| {});
+    let a = db.snapshot();
+    let b = db.snapshot();
+    assert!(Arc::ptr_eq(&a, &b));
+
+    db.update(|v| v.push(2));
+    assert_eq!(*a, [1]);
+    assert_eq!(*db.snapshot(), [1, 2]);
+    drop((a, b));
+
+    let c = db.snapshot();
+    drop(c);
+    let before = Arc::as_ptr(&db.snapshot());
+    db.update(|v| v.push(3));
+    assert_eq!(Arc::as_ptr(&db.snapshot()), before);
+
+    let version = db.version();
+    let r = db.try_update(|v| {
+      Arc::make_mut(v).clear();
+      Err::<(), _>("floppa")
+    });
+    assert_eq!(r, Err("floppa"));
+    assert_eq!(*db.snapshot(), [1, 2, 3]);
+    assert_eq!(db.version(), version);
+    let panicked = panic::catch_unwind(AssertUnwindSafe(|| {
+      db.try_update(|v| -> Result<(), ()> {
+        Arc::make_mut(v).clear();
+        panic!("floppa")
+      })
+    }));
+    assert!(panicked.is_err());
+    assert_eq!(*db.snapshot(), [1, 2, 3]);
+    assert_eq!(db.version(), version);
+    assert_eq!(db.try_update(|v| Ok::<_, ()>(v.len())), Ok(3));
+    assert_eq!(db.version(), version + 1);
+  }
+}
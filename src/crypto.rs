@@ -0,0 +1,248 @@
+use std::time::{Duration, SystemTime};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce, KeyInit, AeadCore};
+use chacha20poly1305::aead::{Aead, OsRng, Payload, rand_core::RngCore};
+use crate::DataError;
+use crate::format::{Encryption, Kdf, WrappedKey};
+
+/// How much longer encrypting makes the payload, the Poly1305 tag.
+pub(crate) const OVERHEAD: usize = 16;
+
+/// What the user gave to encrypt a database with.
+#[derive(Clone)]
+pub(crate) enum Secret {
+  Key([u8; 32]),
+  Password(String),
+}
+
+/// A key that wraps the data key.
+#[derive(Clone)]
+struct Kek {
+  cipher: XChaCha20Poly1305,
+  kdf: Option<Kdf>,
+}
+
+impl Kek {
+  /// Derives the key for `secret`, using `kdf` for passwords or generating a new salt if `None`.
+  fn new(secret: &Secret, kdf: Option<&Kdf>) -> Result<Self, DataError> {
+    let password = match secret {
+      Secret::Key(key) => {
+        return Ok(Self {
+          cipher: XChaCha20Poly1305::new(key.into()),
+          kdf: None,
+        })
+      }
+      Secret::Password(p) => p,
+    };
+    let kdf = kdf.cloned().unwrap_or_else(|| {
+      let mut salt = [0; 16];
+      OsRng.fill_bytes(&mut salt);
+      Kdf {
+        salt,
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+      }
+    });
+    let params =
+      Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, None).map_err(|_| DataError::Decrypt)?;
+    let mut key = [0; 32];
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+      .hash_password_into(password.as_bytes(), &kdf.salt, &mut key)
+      .map_err(|_| DataError::Decrypt)?;
+    Ok(Self {
+      cipher: XChaCha20Poly1305::new(&key.into()),
+      kdf: Some(kdf),
+    })
+  }
+
+  fn wrap(&self, dek: &[u8; 32], expires: Option<SystemTime>) -> WrappedKey {
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    WrappedKey {
+      kdf: self.kdf.clone(),
+      nonce: nonce.into(),
+      key: self.cipher.encrypt(&nonce, &dek[..]).unwrap(),
+      expires,
+    }
+  }
+
+  fn unwrap(&self, wrapped: &WrappedKey) -> Option<[u8; 32]> {
+    let dek = self
+      .cipher
+      .decrypt(XNonce::from_slice(&wrapped.nonce), &wrapped.key[..])
+      .ok()?;
+    dek.try_into().ok()
+  }
+}
+
+#[derive(Clone)]
+pub(crate) struct Cipher {
+  kek: Kek,
+  dek: XChaCha20Poly1305,
+  keys: Vec<WrappedKey>,
+}
+
+impl Cipher {
+  /// Opens the data key of an existing file with `secret`, or creates a new one.
+  pub fn new(secret: &Secret, existing: Option<&Encryption>) -> Result<Self, DataError> {
+    let Some(existing) = existing else {
+      let kek = Kek::new(secret, None)?;
+      let mut dek = [0; 32];
+      OsRng.fill_bytes(&mut dek);
+      return Ok(Self {
+        keys: vec![kek.wrap(&dek, None)],
+        dek: XChaCha20Poly1305::new(&dek.into()),
+        kek,
+      });
+    };
+    let now = SystemTime::now();
+    for wrapped in existing
+      .keys
+      .iter()
+      .filter(|k| k.expires.is_none_or(|e| e > now))
+    {
+      if matches!(secret, Secret::Key(_)) != wrapped.kdf.is_none() {
+        continue;
+      }
+      let kek = Kek::new(secret, wrapped.kdf.as_ref())?;
+      if let Some(dek) = kek.unwrap(wrapped) {
+        return Ok(Self {
+          kek,
+          dek: XChaCha20Poly1305::new(&dek.into()),
+          keys: existing.keys.clone(),
+        });
+      }
+    }
+    Err(DataError::Decrypt)
+  }
+
+  /// Switches to a new data key wrapped by `secret`, which the current key can also unwrap until
+  /// `grace` has passed.
+  pub fn rotate(&mut self, secret: &Secret, grace: Duration) -> Result<(), DataError> {
+    let kek = Kek::new(secret, None)?;
+    let mut dek = [0; 32];
+    OsRng.fill_bytes(&mut dek);
+    self.keys = vec![
+      kek.wrap(&dek, None),
+      self.kek.wrap(&dek, Some(SystemTime::now() + grace)),
+    ];
+    self.dek = XChaCha20Poly1305::new(&dek.into());
+    self.kek = kek;
+    Ok(())
+  }
+
+  /// Describes the encryption of the next save with a fresh nonce, dropping expired keys.
+  pub fn encryption(&self) -> Encryption {
+    let now = SystemTime::now();
+    Encryption {
+      keys: self
+        .keys
+        .iter()
+        .filter(|k| k.expires.is_none_or(|e| e > now))
+        .cloned()
+        .collect(),
+      nonce: XChaCha20Poly1305::generate_nonce(&mut OsRng).into(),
+    }
+  }
+
+  /// Encrypts `payload`, authenticating the header along with it. The result is [`OVERHEAD`]
+  /// bytes longer.
+  pub fn encrypt(&self, e: &Encryption, header: &[u8], payload: &[u8]) -> Vec<u8> {
+    let payload = Payload {
+      msg: payload,
+      aad: header,
+    };
+    self
+      .dek
+      .encrypt(XNonce::from_slice(&e.nonce), payload)
+      .expect("payload too large to encrypt")
+  }
+
+  pub fn decrypt(
+    &self,
+    e: &Encryption,
+    header: &[u8],
+    payload: &[u8],
+  ) -> Result<Vec<u8>, DataError> {
+    let payload = Payload {
+      msg: payload,
+      aad: header,
+    };
+    self
+      .dek
+      .decrypt(XNonce::from_slice(&e.nonce), payload)
+      .map_err(|_| DataError::Decrypt)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::Metadata;
+  use crate::format::Format;
+  use super::*;
+
+  fn format(secret: Secret) -> Format {
+    let mut f = Format::default();
+    f.secret = Some(secret);
+    f
+  }
+
+  fn password(password: &str) -> Format {
+    format(Secret::Password(password.to_string()))
+  }
+
+  #[test]
+  fn test() {
+    let mut f = password("hunter2");
+    f.init(None).unwrap();
+    let bytes = f.encode(b"secret".to_vec(), &Metadata::new()).unwrap();
+    assert!(!bytes.windows(6).any(|w| w == b"secret"));
+
+    assert_eq!(
+      password("hunter2").decode(&bytes).unwrap().0,
+      &b"secret"[..]
+    );
+    assert!(matches!(
+      password("password").decode(&bytes),
+      Err(DataError::Decrypt)
+    ));
+    assert!(matches!(
+      Format::default().decode(&bytes),
+      Err(DataError::Encrypted)
+    ));
+  }
+
+  #[test]
+  fn unencrypted() {
+    let plain = Format::default()
+      .encode(b"planted".to_vec(), &Metadata::new())
+      .unwrap();
+    for bytes in [&plain[..], b"planted"] {
+      assert!(matches!(
+        password("hunter2").decode(bytes),
+        Err(DataError::Unencrypted)
+      ));
+    }
+    let mut f = password("hunter2");
+    f.allow_unencrypted = true;
+    assert_eq!(f.decode(&plain).unwrap().0, &b"planted"[..]);
+  }
+
+  #[test]
+  fn rotate() {
+    let mut f = format(Secret::Key([1; 32]));
+    f.init(None).unwrap();
+    f.rotate_key([2; 32], Duration::from_secs(60)).unwrap();
+    let bytes = f.encode(vec![1], &Metadata::new()).unwrap();
+    assert!(format(Secret::Key([1; 32])).decode(&bytes).is_ok());
+    assert!(format(Secret::Key([2; 32])).decode(&bytes).is_ok());
+
+    f.rotate_key([3; 32], Duration::ZERO).unwrap();
+    let bytes = f.encode(vec![1], &Metadata::new()).unwrap();
+    assert!(matches!(
+      format(Secret::Key([2; 32])).decode(&bytes),
+      Err(DataError::Decrypt)
+    ));
+    assert!(format(Secret::Key([3; 32])).decode(&bytes).is_ok());
+  }
+}
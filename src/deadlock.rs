@@ -0,0 +1,107 @@
+//! Tracks which thread holds which guard, see the `debug-deadlock` feature.
+//!
+//! Without the feature [`acquire`] does nothing and [`Held`] is empty.
+
+#[cfg(feature = "debug-deadlock")]
+use std::backtrace::Backtrace;
+#[cfg(feature = "debug-deadlock")]
+use std::collections::HashMap;
+#[cfg(feature = "debug-deadlock")]
+use std::sync::atomic::{AtomicU64, Ordering};
+#[cfg(feature = "debug-deadlock")]
+use std::sync::{LazyLock, Mutex};
+#[cfg(feature = "debug-deadlock")]
+use std::thread::{self, ThreadId};
+
+#[cfg(feature = "debug-deadlock")]
+struct Holder {
+  id: u64,
+  thread: ThreadId,
+  write: bool,
+  backtrace: Backtrace,
+}
+
+/// The guards currently held, by the address of their lock.
+#[cfg(feature = "debug-deadlock")]
+static HELD: LazyLock<Mutex<HashMap<usize, Vec<Holder>>>> = LazyLock::new(Mutex::default);
+
+#[cfg(feature = "debug-deadlock")]
+static NEXT: AtomicU64 = AtomicU64::new(0);
+
+/// Forgets the guard when dropped, kept alongside the lock guard it belongs to.
+pub(crate) struct Held {
+  #[cfg(feature = "debug-deadlock")]
+  lock: usize,
+  #[cfg(feature = "debug-deadlock")]
+  id: u64,
+}
+
+/// Records that the current thread is about to lock `lock`.
+///
+/// Panics if the thread already holds a guard for it that the new one would wait on forever,
+/// which is any guard for a write and a write guard for a read. The message has the backtraces
+/// of both acquisitions.
+#[cfg(feature = "debug-deadlock")]
+pub(crate) fn acquire<L>(lock: &L, write: bool) -> Held {
+  let lock = lock as *const L as usize;
+  let thread = thread::current().id();
+  let mut held = HELD.lock().unwrap();
+  let holders = held.entry(lock).or_default();
+  if let Some(h) = holders
+    .iter()
+    .find(|h| h.thread == thread && (write || h.write))
+  {
+    let message = format!(
+      "deadlock: thread {:?} tried to {} a lock it already holds a {} guard for\n\n\
+       first acquired at:\n{}\n\nthen acquired at:\n{}",
+      thread::current().name().unwrap_or("<unnamed>"),
+      if write { "write" } else { "read" },
+      if h.write { "write" } else { "read" },
+      h.backtrace,
+      Backtrace::force_capture(),
+    );
+    drop(held);
+    panic!("{message}");
+  }
+  let id = NEXT.fetch_add(1, Ordering::Relaxed);
+  holders.push(Holder {
+    id,
+    thread,
+    write,
+    backtrace: Backtrace::force_capture(),
+  });
+  Held { lock, id }
+}
+
+#[cfg(not(feature = "debug-deadlock"))]
+pub(crate) fn acquire<L>(_: &L, _: bool) -> Held {
+  Held {}
+}
+
+#[cfg(feature = "debug-deadlock")]
+impl Drop for Held {
+  fn drop(&mut self) {
+    let mut held = HELD.lock().unwrap();
+    if let Some(holders) = held.get_mut(&self.lock) {
+      holders.retain(|h| h.id != self.id);
+      if holders.is_empty() {
+        held.remove(&self.lock);
+      }
+    }
+  }
+}
+
+#[cfg(all(test, feature = "debug-deadlock"))]
+mod test {
+  use crate::Database;
+
+  #[test]
+  #[should_panic(expected = "already holds a read guard")]
+  fn test() {
+    let db = Database::new_custom(0, |_| {});
+    let a = db.get();
+    let b = db.get();
+    assert_eq!(*a, *b);
+    *db.get_mut() += 1;
+  }
+}
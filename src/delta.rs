@@ -0,0 +1,141 @@
+use serde::{Serialize, Deserialize};
+use sha2::{Digest as _, Sha256};
+use crate::DataError;
+
+const CHUNK: usize = 4096;
+
+/// A SHA-256 digest, which unlike `DefaultHasher` is the same across Rust releases, so it can be
+/// compared with one computed by an older build.
+pub(crate) type Digest = [u8; 32];
+
+/// The chunks of the serialized data that changed since the last snapshot.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Delta {
+  /// The [`id`] of the snapshot this applies to.
+  pub base: Digest,
+  len: u64,
+  chunks: Vec<(u64, Vec<u8>)>,
+}
+
+impl Delta {
+  /// Applies the delta to the snapshot `payload`, failing with [`DataError::BadDelta`] if a chunk
+  /// doesn't fit.
+  pub fn apply(self, payload: &mut Vec<u8>) -> Result<(), DataError> {
+    let len = usize::try_from(self.len).map_err(|_| DataError::BadDelta)?;
+    let fits = |i: u64, chunk: &[u8]| {
+      let start = usize::try_from(i).ok()?.checked_mul(CHUNK)?;
+      Some(start..start.checked_add(chunk.len()).filter(|&end| end <= len)?)
+    };
+    let ranges = self
+      .chunks
+      .iter()
+      .map(|(i, chunk)| fits(*i, chunk).ok_or(DataError::BadDelta))
+      .collect::<Result<Vec<_>, _>>()?;
+    payload.resize(len, 0);
+    for (range, (_, chunk)) in ranges.into_iter().zip(self.chunks) {
+      payload[range].copy_from_slice(&chunk);
+    }
+    Ok(())
+  }
+}
+
+/// Tracks the last snapshot so saves can write only what changed since.
+///
+/// Only a digest of each chunk of the snapshot is kept, not the snapshot itself.
+pub(crate) struct Deltas {
+  snapshot_every: u32,
+  saves: u32,
+  base: Option<(Digest, Vec<Digest>)>,
+}
+
+impl Deltas {
+  pub fn new(snapshot_every: u32) -> Self {
+    Self {
+      snapshot_every,
+      saves: 0,
+      base: None,
+    }
+  }
+
+  /// Records that a snapshot with the chunk [`hashes`] was saved.
+  pub fn rebase(&mut self, hashes: Vec<Digest>) {
+    self.saves = 0;
+    self.base = Some((id(&hashes), hashes));
+  }
+
+  /// Returns the delta to save for `payload`, or `None` when a full snapshot is due, either
+  /// because enough deltas were saved or because the delta would be more than half of the data.
+  pub fn diff(&mut self, payload: &[u8]) -> Option<Delta> {
+    let (base, hashes) = self.base.as_ref()?;
+    if self.saves + 1 >= self.snapshot_every {
+      return None;
+    }
+    let mut size = 0;
+    let mut chunks = vec![];
+    for (i, chunk) in payload.chunks(CHUNK).enumerate() {
+      if hashes.get(i) != Some(&hash(chunk)) {
+        size += chunk.len();
+        if size > payload.len() / 2 {
+          return None;
+        }
+        chunks.push((i as u64, chunk.to_vec()));
+      }
+    }
+    self.saves += 1;
+    Some(Delta {
+      base: *base,
+      len: payload.len() as u64,
+      chunks,
+    })
+  }
+}
+
+/// Hashes each chunk of `payload`.
+pub fn hashes(payload: &[u8]) -> Vec<Digest> {
+  payload.chunks(CHUNK).map(hash).collect()
+}
+
+/// Identifies a snapshot by its content, from the [`hashes`] of its chunks, so a delta is only
+/// applied to the snapshot it was made from.
+pub fn id(hashes: &[Digest]) -> Digest {
+  let mut h = Sha256::new();
+  for hash in hashes {
+    h.update(hash);
+  }
+  h.finalize().into()
+}
+
+fn hash(chunk: &[u8]) -> Digest {
+  Sha256::digest(chunk).into()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let mut base = vec![0; CHUNK * 10 + 10];
+    let mut deltas = Deltas::new(10);
+    let snapshot = id(&hashes(&base));
+    deltas.rebase(hashes(&base));
+
+    let mut payload = base.clone();
+    payload[CHUNK * 3] = 1;
+    payload.truncate(CHUNK * 9 + 3);
+    let delta = deltas.diff(&payload).unwrap();
+    assert_eq!((delta.base, delta.chunks.len()), (snapshot, 2));
+    delta.apply(&mut base).unwrap();
+    assert_eq!(base, payload);
+
+    assert_ne!(id(&hashes(&base)), snapshot);
+    let delta = Delta {
+      base: snapshot,
+      len: 10,
+      chunks: vec![(0, vec![1; 11])],
+    };
+    assert!(matches!(delta.apply(&mut base), Err(DataError::BadDelta)));
+
+    assert!(deltas.diff(&[1; CHUNK * 10]).is_none());
+  }
+}
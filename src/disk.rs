@@ -0,0 +1,651 @@
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read, Write};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::backup::Backups;
+use crate::canonical;
+use crate::delta::{self, Delta, Deltas};
+use crate::format::Format;
+use crate::profile::Profiler;
+use crate::{DataError, Metadata};
+
+/// A file written with a [`Format`], shared by the saver and the database.
+pub(crate) struct Disk {
+  target: Target,
+  pub format: Mutex<Format>,
+  pub options: OpenOptions,
+  /// Serializes with [`canonical::serialize`], see [`crate::Builder::canonical`].
+  pub canonical: bool,
+  /// The last payload saved in canonical mode.
+  last: Mutex<Option<Vec<u8>>>,
+  pub profiler: Option<Profiler>,
+  #[cfg(feature = "testing")]
+  pub faults: Option<crate::testing::FaultInjector>,
+}
+
+/// Creates a writer to save the whole file to, see [`crate::Builder::open_with`].
+pub(crate) type Writer = Box<dyn Fn() -> io::Result<Box<dyn Write>> + Send + Sync>;
+
+enum Target {
+  File {
+    path: PathBuf,
+    deltas: Option<Mutex<Deltas>>,
+  },
+  Writer(Writer),
+}
+
+/// What opening a database does when its file is empty or was cut short, e.g. by a power loss
+/// while a file was written in place. See [`crate::Builder::on_truncated`].
+pub enum OnTruncated {
+  /// Fail with [`DataError::Truncated`].
+  Fail,
+  /// Start from the default data, calling the function with the path first to warn about it.
+  Reset(Box<dyn Fn(&Path) + Send + Sync>),
+  /// Load the newest backup that can be read, failing if there is none.
+  Restore(Backups),
+}
+
+impl Disk {
+  /// Opens the file at `path`, applying its delta file if there is one.
+  ///
+  /// With `mmap` the file is mapped instead of read, see [`crate::Builder::mmap`].
+  pub fn open<T: DeserializeOwned + Default>(
+    path: PathBuf,
+    format: Format,
+    snapshot_every: Option<u32>,
+    mmap: bool,
+  ) -> Result<(Self, T, Option<Metadata>), DataError> {
+    let on_truncated = &OnTruncated::Fail;
+    Self::open_with(
+      path,
+      format,
+      snapshot_every,
+      mmap,
+      false,
+      on_truncated,
+      |payload| {
+        Ok(match payload {
+          Some(payload) => bincode::deserialize(payload)?,
+          None => T::default(),
+        })
+      },
+    )
+  }
+
+  /// Like [`Disk::open`], but the payload is deserialized by `load`, which gets `None` when the
+  /// file doesn't exist, and truncated files are handled according to `on_truncated`.
+  ///
+  /// With `canonical` the payload is kept, so a first save that doesn't change it is skipped.
+  pub fn open_with<T, L: FnMut(Option<&[u8]>) -> Result<T, DataError>>(
+    path: PathBuf,
+    mut format: Format,
+    snapshot_every: Option<u32>,
+    mmap: bool,
+    canonical: bool,
+    on_truncated: &OnTruncated,
+    mut load: L,
+  ) -> Result<(Self, T, Option<Metadata>), DataError> {
+    let mut deltas = snapshot_every.map(Deltas::new);
+    let mut last = None;
+    let mut load = |payload: Option<&[u8]>| {
+      if canonical {
+        last = payload.map(<[u8]>::to_vec);
+      }
+      load(payload)
+    };
+    let loaded = read_file(&path, &mut format, deltas.as_mut(), mmap, &mut load);
+    let mut restored = false;
+    let (data, metadata) = match (loaded, on_truncated) {
+      (Err(DataError::Truncated), OnTruncated::Reset(f)) => {
+        f(&path);
+        format.init(None)?;
+        (load(None)?, None)
+      }
+      (Err(DataError::Truncated), OnTruncated::Restore(backups)) => {
+        restored = true;
+        backups
+          .list()?
+          .iter()
+          .rev()
+          .find_map(|(backup, _)| read_file(backup, &mut format, None, false, &mut load).ok())
+          .ok_or(DataError::Truncated)?
+      }
+      (loaded, _) => loaded?,
+    };
+    // a backup isn't what the file holds, so the first save still rewrites it
+    if restored {
+      last = None;
+    }
+    let disk = Self {
+      target: Target::File {
+        path,
+        deltas: deltas.map(Mutex::new),
+      },
+      format: Mutex::new(format),
+      options: OpenOptions::default(),
+      canonical,
+      last: Mutex::new(last),
+      profiler: None,
+      #[cfg(feature = "testing")]
+      faults: None,
+    };
+    Ok((disk, data, metadata))
+  }
+
+  /// Reads the file from `reader`, which is empty for a new database.
+  pub fn read<T: DeserializeOwned + Default, R: Read>(
+    mut reader: R,
+    mut format: Format,
+    writer: Writer,
+  ) -> Result<(Self, T, Option<Metadata>), DataError> {
+    let mut bytes = vec![];
+    reader.read_to_end(&mut bytes)?;
+    let (data, metadata) = if bytes.is_empty() {
+      format.init(None)?;
+      (T::default(), None)
+    } else {
+      let (payload, metadata) = format.decode(&bytes)?;
+      (bincode::deserialize(&payload)?, metadata)
+    };
+    let disk = Self {
+      target: Target::Writer(writer),
+      format: Mutex::new(format),
+      options: OpenOptions::default(),
+      canonical: false,
+      last: Mutex::new(None),
+      profiler: None,
+      #[cfg(feature = "testing")]
+      faults: None,
+    };
+    Ok((disk, data, metadata))
+  }
+
+  /// The file it writes, `None` for a writer.
+  pub fn path(&self) -> Option<&Path> {
+    match &self.target {
+      Target::File { path, .. } => Some(path),
+      Target::Writer(_) => None,
+    }
+  }
+
+  /// Saves `data`, returning how many bytes were written.
+  pub fn save<T: Serialize>(&self, data: &T, metadata: &Metadata) -> Result<usize, DataError> {
+    self.save_payload(self.serialize(data)?, metadata)
+  }
+
+  /// Serializes `data` like [`Disk::save`] does.
+  pub fn serialize<T: Serialize>(&self, data: &T) -> Result<Vec<u8>, DataError> {
+    let start = Instant::now();
+    let payload = match self.canonical {
+      true => canonical::serialize(data)?,
+      false => bincode::serialize(data)?,
+    };
+    if let Some(profiler) = &self.profiler {
+      profiler.serialized(start.elapsed());
+    }
+    Ok(payload)
+  }
+
+  /// Saves data that has already been serialized.
+  ///
+  /// In canonical mode nothing is written when the payload didn't change since the last save, and
+  /// the creation time is written as `modified`, so equal data always gives the same file.
+  pub fn save_payload(&self, payload: Vec<u8>, metadata: &Metadata) -> Result<usize, DataError> {
+    if !self.canonical {
+      return self.write_payload(payload, metadata);
+    }
+    let mut last = self.last.lock().unwrap();
+    if last.as_ref() == Some(&payload) {
+      return Ok(0);
+    }
+    let metadata = Metadata {
+      modified: metadata.created,
+      ..metadata.clone()
+    };
+    let bytes = self.write_payload(payload.clone(), &metadata)?;
+    *last = Some(payload);
+    Ok(bytes)
+  }
+
+  fn write_payload(&self, payload: Vec<u8>, metadata: &Metadata) -> Result<usize, DataError> {
+    let Some(profiler) = &self.profiler else {
+      return self.write_unprofiled(payload, metadata);
+    };
+    let changed = profiler.changed(&payload);
+    let start = Instant::now();
+    let written = self.write_unprofiled(payload, metadata)?;
+    profiler.saved(changed, written, start.elapsed());
+    Ok(written)
+  }
+
+  fn write_unprofiled(&self, payload: Vec<u8>, metadata: &Metadata) -> Result<usize, DataError> {
+    let format = self.format.lock().unwrap();
+    if let Target::File {
+      path,
+      deltas: Some(deltas),
+    } = &self.target
+    {
+      if let Some(delta) = deltas.lock().unwrap().diff(&payload) {
+        let bytes = format.encode(bincode::serialize(&delta)?, metadata)?;
+        self.write(&delta_path(path), &bytes)?;
+        return Ok(bytes.len());
+      }
+    }
+    self.snapshot(&format, payload, metadata)
+  }
+
+  /// Writes the full data with `format`, starting a new base for deltas.
+  pub fn snapshot(
+    &self,
+    format: &Format,
+    payload: Vec<u8>,
+    metadata: &Metadata,
+  ) -> Result<usize, DataError> {
+    // only the hashes of the snapshot are kept, so the payload isn't copied
+    let deltas = match &self.target {
+      Target::File { deltas, .. } => deltas.as_ref().map(|d| (d, delta::hashes(&payload))),
+      Target::Writer(_) => None,
+    };
+    let bytes = format.encode(payload, metadata)?;
+    match &self.target {
+      Target::File { path, .. } => {
+        self.write(path, &bytes)?;
+        if let Some((deltas, hashes)) = deltas {
+          deltas.lock().unwrap().rebase(hashes);
+          match fs::remove_file(delta_path(path)) {
+            Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+          }
+        }
+      }
+      Target::Writer(writer) => {
+        let mut w = writer()?;
+        w.write_all(&bytes)?;
+        w.flush()?;
+      }
+    }
+    Ok(bytes.len())
+  }
+
+  fn write(&self, path: &Path, bytes: &[u8]) -> Result<(), DataError> {
+    #[cfg(feature = "testing")]
+    let fault = self.faults.as_ref().and_then(|f| f.take());
+    #[cfg(not(feature = "testing"))]
+    let fault = None;
+    write_atomic_with(path, bytes, self.options, fault)
+  }
+}
+
+/// The bytes of a file, either read into memory or mapped.
+enum Contents {
+  Read(Vec<u8>),
+  #[cfg(feature = "mmap")]
+  Mapped(memmap2::Mmap),
+}
+
+impl Contents {
+  #[cfg_attr(not(feature = "mmap"), allow(unused_variables))]
+  fn read(path: &Path, mmap: bool) -> io::Result<Self> {
+    #[cfg(feature = "mmap")]
+    if mmap {
+      // safety: the file is only ever replaced by renaming over it, never modified in place
+      return Ok(Self::Mapped(unsafe {
+        memmap2::Mmap::map(&File::open(path)?)?
+      }));
+    }
+    fs::read(path).map(Self::Read)
+  }
+}
+
+impl Deref for Contents {
+  type Target = [u8];
+
+  fn deref(&self) -> &[u8] {
+    match self {
+      Self::Read(bytes) => bytes,
+      #[cfg(feature = "mmap")]
+      Self::Mapped(map) => map,
+    }
+  }
+}
+
+fn delta_path(path: &Path) -> PathBuf {
+  let mut path = path.to_path_buf().into_os_string();
+  path.push(".delta");
+  path.into()
+}
+
+fn read_delta(format: &mut Format, path: &Path) -> Result<Option<(Delta, Metadata)>, DataError> {
+  let bytes = match fs::read(path) {
+    Ok(b) => b,
+    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+  let (payload, metadata) = format.decode(&bytes)?;
+  Ok(
+    metadata
+      .map(|m| bincode::deserialize(&payload).map(|d| (d, m)))
+      .transpose()?,
+  )
+}
+
+/// Reads and loads the file at `path`, along with its delta file if there are `deltas`.
+///
+/// Fails with [`DataError::Truncated`] if the file is empty or ends unexpectedly.
+fn read_file<T, L: FnMut(Option<&[u8]>) -> Result<T, DataError>>(
+  path: &Path,
+  format: &mut Format,
+  mut deltas: Option<&mut Deltas>,
+  mmap: bool,
+  load: &mut L,
+) -> Result<(T, Option<Metadata>), DataError> {
+  let bytes = match Contents::read(path, mmap) {
+    Ok(bytes) => bytes,
+    Err(e) if e.kind() == ErrorKind::NotFound => {
+      format.init(None)?;
+      return Ok((load(None)?, None));
+    }
+    Err(e) => return Err(e.into()),
+  };
+  // an empty protobuf message is valid
+  if bytes.is_empty() && !format.raw {
+    return Err(DataError::Truncated);
+  }
+  let loaded = (|| {
+    let (mut payload, mut metadata) = format.decode(&bytes)?;
+    // files from before headers existed have no deltas
+    let mut hashes = None;
+    if metadata.is_some() {
+      if let Some((delta, m)) = read_delta(format, &delta_path(path))? {
+        let base = hashes.insert(delta::hashes(&payload));
+        if delta.base == delta::id(base) {
+          delta.apply(payload.to_mut())?;
+          metadata = Some(m);
+        }
+      }
+    }
+    if let (Some(deltas), Some(_)) = (&mut deltas, &metadata) {
+      deltas.rebase(hashes.unwrap_or_else(|| delta::hashes(&payload)));
+    }
+    Ok((load(Some(&payload))?, metadata))
+  })();
+  match loaded {
+    Err(DataError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => Err(DataError::Truncated),
+    Err(DataError::Bincode(e)) if matches!(&*e, bincode::ErrorKind::Io(e) if e.kind() == ErrorKind::UnexpectedEof) => {
+      Err(DataError::Truncated)
+    }
+    loaded => loaded,
+  }
+}
+
+/// Writes to a temporary file next to `path` and renames it over the original, so the file is
+/// either fully the old or fully the new contents. Returns once both are synced to disk.
+pub(crate) fn write_atomic(path: &Path, bytes: &[u8]) -> Result<(), DataError> {
+  write_atomic_with(path, bytes, OpenOptions::default(), None)
+}
+
+fn write_atomic_with(
+  path: &Path,
+  bytes: &[u8],
+  options: OpenOptions,
+  fault: Option<Fault>,
+) -> Result<(), DataError> {
+  let injected = || Err(io::Error::other("injected fault").into());
+  let mut tmp = path.to_path_buf().into_os_string();
+  tmp.push(".tmp");
+  let tmp = PathBuf::from(tmp);
+  let unnamed = options.tmpfile.then(|| create_unnamed(path)).flatten();
+  let linked = unnamed.is_some();
+  let mut f = match unnamed {
+    Some(f) => f,
+    None => options.create(&tmp)?,
+  };
+  if let Some(Fault::Write(n)) = fault {
+    f.write_all(&bytes[..n.min(bytes.len())])?;
+    return injected();
+  }
+  f.write_all(bytes)?;
+  if fault == Some(Fault::Sync) {
+    return injected();
+  }
+  f.sync_all()?;
+  if linked {
+    link(&f, &tmp)?;
+  }
+  if fault == Some(Fault::Rename) {
+    return injected();
+  }
+  fs::rename(&tmp, path)?;
+  // the rename itself is only durable once the directory is synced
+  #[cfg(unix)]
+  match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => File::open(dir)?.sync_all()?,
+    _ => File::open(".")?.sync_all()?,
+  }
+  Ok(())
+}
+
+/// Platform specific ways of writing the file, see [`crate::Builder::open_options`].
+///
+/// Options that don't apply to the current platform are ignored.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct OpenOptions {
+  tmpfile: bool,
+  exclusive: bool,
+}
+
+impl OpenOptions {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// On Linux, writes the new file with `O_TMPFILE` and only links it next to the database once
+  /// it is complete, so a crash can never leave a partial temporary file behind.
+  ///
+  /// Falls back to a named temporary file on filesystems without `O_TMPFILE`.
+  pub fn tmpfile(mut self, tmpfile: bool) -> Self {
+    self.tmpfile = tmpfile;
+    self
+  }
+
+  /// On Windows, writes the new file without sharing write or delete access, so other processes
+  /// can't truncate or replace it while it is being saved.
+  pub fn exclusive(mut self, exclusive: bool) -> Self {
+    self.exclusive = exclusive;
+    self
+  }
+
+  fn create(&self, path: &Path) -> io::Result<File> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(windows)]
+    if self.exclusive {
+      use std::os::windows::fs::OpenOptionsExt;
+      const FILE_SHARE_READ: u32 = 1;
+      options.share_mode(FILE_SHARE_READ);
+    }
+    options.open(path)
+  }
+}
+
+/// Creates a file without a name in the directory of `path`, or `None` if that isn't supported.
+#[cfg(target_os = "linux")]
+fn create_unnamed(path: &Path) -> Option<File> {
+  use std::ffi::CString;
+  use std::os::fd::FromRawFd;
+  use std::os::unix::ffi::OsStrExt;
+  let dir = match path.parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+  let dir = CString::new(dir.as_os_str().as_bytes()).ok()?;
+  let flags = libc::O_TMPFILE | libc::O_WRONLY | libc::O_CLOEXEC;
+  let fd = unsafe { libc::open(dir.as_ptr(), flags, 0o666) };
+  // safety: the fd was just opened and isn't owned by anything else
+  (fd >= 0).then(|| unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn create_unnamed(_: &Path) -> Option<File> {
+  None
+}
+
+/// Gives a file from [`create_unnamed`] the name `path`.
+#[cfg(target_os = "linux")]
+fn link(f: &File, path: &Path) -> io::Result<()> {
+  use std::ffi::CString;
+  use std::os::fd::AsRawFd;
+  use std::os::unix::ffi::OsStrExt;
+  match fs::remove_file(path) {
+    Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+    _ => {}
+  }
+  // linking the fd directly with AT_EMPTY_PATH needs CAP_DAC_READ_SEARCH, going through /proc
+  // doesn't
+  let fd = CString::new(format!("/proc/self/fd/{}", f.as_raw_fd()))?;
+  let path = CString::new(path.as_os_str().as_bytes())?;
+  let linked = unsafe {
+    libc::linkat(
+      libc::AT_FDCWD,
+      fd.as_ptr(),
+      libc::AT_FDCWD,
+      path.as_ptr(),
+      libc::AT_SYMLINK_FOLLOW,
+    )
+  };
+  match linked {
+    0 => Ok(()),
+    _ => Err(io::Error::last_os_error()),
+  }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn link(_: &File, _: &Path) -> io::Result<()> {
+  unreachable!("unnamed files are only created on linux")
+}
+
+/// A failure to inject into the next write, see [`crate::testing::FaultInjector`].
+#[cfg_attr(not(feature = "testing"), allow(dead_code))]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fault {
+  /// Fail after writing this many bytes of the new file, as if the process was killed.
+  Write(usize),
+  /// Fail to sync the new file to disk.
+  Sync,
+  /// Fail to rename the new file over the old one.
+  Rename,
+}
+
+#[cfg(test)]
+mod test {
+  use std::time::SystemTime;
+  use super::*;
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-disk.db");
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_file(delta_path(&path));
+    let (disk, _, _) =
+      Disk::open::<Vec<u8>>(path.clone(), Format::default(), Some(10), false).unwrap();
+    let mut data = vec![0; 100_000];
+    let mut metadata = Metadata::new();
+    disk
+      .snapshot(
+        &Format::default(),
+        bincode::serialize(&data).unwrap(),
+        &metadata,
+      )
+      .unwrap();
+    data[50_000] = 1;
+    metadata.modified = SystemTime::now();
+    disk.save(&data, &metadata).unwrap();
+    assert!(delta_path(&path).exists());
+
+    let mmap = cfg!(feature = "mmap");
+    let (disk, reopened, m) =
+      Disk::open::<Vec<u8>>(path.clone(), Format::default(), None, mmap).unwrap();
+    assert_eq!(reopened, data);
+    assert_eq!(m.unwrap().modified, metadata.modified);
+
+    // a snapshot saved without delta saves leaves the delta behind, which no longer applies even
+    // with the same modified time
+    let other = vec![2; 100_000];
+    disk.save(&other, &metadata).unwrap();
+    let (_, reopened, _) = Disk::open::<Vec<u8>>(path, Format::default(), None, false).unwrap();
+    assert_eq!(reopened, other);
+
+    // only a missing file starts from the default
+    let dir = std::env::temp_dir();
+    assert!(Disk::open::<Vec<u8>>(dir, Format::default(), None, false).is_err());
+  }
+
+  #[test]
+  fn options() {
+    let path = std::env::temp_dir().join("floppadb-tmpfile.db");
+    let options = OpenOptions::new().tmpfile(true).exclusive(true);
+    write_atomic_with(&path, b"floppa", options, None).unwrap();
+    write_atomic_with(&path, b"bingus", options, None).unwrap();
+    assert_eq!(fs::read(&path).unwrap(), b"bingus");
+    assert!(write_atomic_with(&path, b"sogga", options, Some(Fault::Rename)).is_err());
+    assert_eq!(fs::read(&path).unwrap(), b"bingus");
+  }
+
+  #[test]
+  fn truncated() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::{Builder, Database};
+
+    let dir = std::env::temp_dir().join("floppadb-truncated");
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("data.db");
+    let db = Database::<Vec<u32>>::builder()
+      .manual_save()
+      .open(&path)
+      .unwrap();
+    db.get_mut().push(1);
+    db.backup_now(dir.join("backup-1.db")).unwrap();
+    db.close().unwrap();
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..bytes.len() - 2]).unwrap();
+
+    let open = |on_truncated| {
+      Builder::<Vec<u32>>::new()
+        .manual_save()
+        .on_truncated(on_truncated)
+        .open(&path)
+    };
+    assert!(matches!(open(OnTruncated::Fail), Err(DataError::Truncated)));
+    let backups = Backups::new(&dir, Duration::from_secs(60));
+    assert_eq!(*open(OnTruncated::Restore(backups)).unwrap().get(), [1]);
+
+    fs::write(&path, []).unwrap();
+    let warned = Arc::new(AtomicBool::new(false));
+    let w = warned.clone();
+    let reset = OnTruncated::Reset(Box::new(move |_| w.store(true, Ordering::Relaxed)));
+    assert!(open(reset).unwrap().get().is_empty());
+    assert!(warned.load(Ordering::Relaxed));
+
+    // signed and encrypted files are caught by their length before they fail to verify
+    let builders: Vec<Builder<Vec<u32>>> = vec![
+      #[cfg(feature = "signing")]
+      Builder::new().sign("key", crate::OnTamper::Refuse),
+      #[cfg(feature = "encryption")]
+      Builder::new().key([0; 32]),
+    ];
+    for builder in builders {
+      let bytes = builder.to_bytes(&vec![1u32]).unwrap();
+      fs::write(&path, &bytes[..bytes.len() - 2]).unwrap();
+      assert!(matches!(
+        builder.manual_save().open(&path),
+        Err(DataError::Truncated)
+      ));
+    }
+  }
+}
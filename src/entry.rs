@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::mem::ManuallyDrop;
+use std::ops::{Deref, DerefMut};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{deadlock, Database, Inner};
+
+impl<K, V> Database<HashMap<K, RwLock<V>>>
+where
+  K: Serialize + DeserializeOwned + Eq + Hash + Send + Sync + 'static,
+  V: Serialize + DeserializeOwned + Default + Send + Sync + 'static,
+{
+  guard_fn! {
+    /// Locks the value at `key`, inserting a default if it is absent.
+    ///
+    /// The map itself is only read locked while the guard is held, so guards for different keys
+    /// don't block each other. Each value needs its own lock for this to be sound, hence the
+    /// `RwLock<V>` values.
+    fn entry(&self, key: K) -> EntryGuard<'_, K, V> {
+      let held = deadlock::acquire(&*self.0, false);
+      let map = self.0.read().unwrap();
+      let (map, value) = match map.data.get(&key) {
+        Some(v) => {
+          let v = v as *const RwLock<V>;
+          (map, v)
+        }
+        None => {
+          drop(map);
+          let mut map = self.0.write().unwrap();
+          let v = map.data.entry(key).or_default() as *const RwLock<V>;
+          (RwLockWriteGuard::downgrade(map), v)
+        }
+      };
+      // the map can't be modified while the read guard is held, which outlives the value guard
+      let value = unsafe { &*value };
+      EntryGuard {
+        _held: [held, deadlock::acquire(value, true)],
+        value: ManuallyDrop::new(value.write().unwrap()),
+        map,
+      }
+    }
+  }
+}
+
+/// Exclusive access to a single value of a map, see [`Database::entry`].
+pub struct EntryGuard<'a, K, V> {
+  value: ManuallyDrop<RwLockWriteGuard<'a, V>>,
+  map: RwLockReadGuard<'a, Inner<HashMap<K, RwLock<V>>>>,
+  /// For the map and the value.
+  _held: [deadlock::Held; 2],
+}
+
+impl<K, V> Deref for EntryGuard<'_, K, V> {
+  type Target = V;
+
+  fn deref(&self) -> &V {
+    &self.value
+  }
+}
+
+impl<K, V> DerefMut for EntryGuard<'_, K, V> {
+  fn deref_mut(&mut self) -> &mut V {
+    &mut self.value
+  }
+}
+
+impl<K, V> Drop for EntryGuard<'_, K, V> {
+  fn drop(&mut self) {
+    // unlock the value first so watchers can read it
+    unsafe { ManuallyDrop::drop(&mut self.value) };
+    self.map.commit(None);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::thread;
+  use std::sync::mpsc;
+  use super::*;
+
+  #[test]
+  fn test() {
+    let db = Database::new_custom(HashMap::<String, RwLock<u32>>::new(), |_| {});
+    let (tx, rx) = mpsc::channel();
+    let d = db.clone();
+    let a = thread::spawn(move || {
+      let mut a = d.entry("a".to_string());
+      rx.recv().unwrap();
+      *a += 1;
+    });
+    *db.entry("b".to_string()) += 2;
+    tx.send(()).unwrap();
+    a.join().unwrap();
+    let map = db.get();
+    assert_eq!(*map["a"].read().unwrap(), 1);
+    assert_eq!(*map["b"].read().unwrap(), 2);
+  }
+}
@@ -0,0 +1,145 @@
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum DataError {
+  Io(io::Error),
+  #[cfg(feature = "bincode")]
+  Bincode(bincode::Error),
+  #[cfg(feature = "rkyv")]
+  Rkyv(rkyv::rancor::Error),
+  #[cfg(feature = "prost")]
+  Prost(prost::DecodeError),
+  #[cfg(feature = "yaml")]
+  Yaml(serde_yaml::Error),
+  #[cfg(feature = "path")]
+  Json(serde_json::Error),
+  /// Nothing exists at the path given to [`crate::Database::get_path`].
+  #[cfg(feature = "path")]
+  NoPath(String),
+  /// The file was written with a newer format than this version understands.
+  UnknownFormat(u8),
+  /// The payload is compressed with an algorithm whose feature isn't enabled.
+  #[cfg(feature = "bincode")]
+  Compression(u8),
+  /// The file is compressed with a zstd dictionary, but none was given, see
+  /// [`crate::Builder::zstd_dictionary`].
+  #[cfg(feature = "zstd")]
+  Dictionary,
+  /// The file isn't encrypted even though a key was given, see
+  /// [`crate::Builder::allow_unencrypted`].
+  #[cfg(feature = "encryption")]
+  Unencrypted,
+  /// The file is encrypted, but no key was given or the `encryption` feature is disabled.
+  Encrypted,
+  /// The file was saved by an app version that [`crate::Builder::check_app_version`] refused.
+  AppVersion(String),
+  /// The named [`crate::Builder`] option isn't supported by how the database is opened.
+  Unsupported(&'static str),
+  /// The database isn't backed by a file, e.g. it was created with [`crate::Database::new_custom`].
+  NoFile,
+  /// The delta file has a chunk outside of the data it applies to, see
+  /// [`crate::Builder::delta_saves`].
+  #[cfg(feature = "bincode")]
+  BadDelta,
+  /// The file is empty or was cut short, see [`crate::Builder::on_truncated`].
+  #[cfg(feature = "bincode")]
+  Truncated,
+  /// A [`crate::LazyDatabase`] already failed to load, with the error returned then.
+  #[cfg(feature = "bincode")]
+  LoadFailed,
+  /// The file couldn't be decrypted, either the key is wrong or the file is corrupted.
+  #[cfg(feature = "encryption")]
+  Decrypt,
+  /// The file's signature doesn't match, see [`crate::Builder::sign`].
+  #[cfg(feature = "signing")]
+  BadSignature,
+  /// An audit log entry doesn't match the chain, at the given line.
+  #[cfg(feature = "audit")]
+  Tampered(usize),
+}
+
+impl fmt::Display for DataError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Self::Io(e) => write!(f, "io error: {}", e),
+      #[cfg(feature = "bincode")]
+      Self::Bincode(e) => write!(f, "bincode error: {}", e),
+      #[cfg(feature = "rkyv")]
+      Self::Rkyv(e) => write!(f, "rkyv error: {}", e),
+      #[cfg(feature = "prost")]
+      Self::Prost(e) => write!(f, "protobuf error: {}", e),
+      #[cfg(feature = "yaml")]
+      Self::Yaml(e) => write!(f, "yaml error: {}", e),
+      #[cfg(feature = "path")]
+      Self::Json(e) => write!(f, "json error: {}", e),
+      #[cfg(feature = "path")]
+      Self::NoPath(path) => write!(f, "no value at path {:?}", path),
+      Self::UnknownFormat(v) => write!(f, "unknown format version {}", v),
+      #[cfg(feature = "bincode")]
+      Self::Compression(id) => write!(f, "unsupported compression {}", id),
+      #[cfg(feature = "zstd")]
+      Self::Dictionary => write!(f, "file needs a zstd dictionary"),
+      Self::Encrypted => write!(f, "file is encrypted"),
+      #[cfg(feature = "encryption")]
+      Self::Unencrypted => write!(f, "file isn't encrypted"),
+      Self::AppVersion(v) => write!(f, "file was saved by app version {}", v),
+      Self::Unsupported(option) => write!(f, "option {} isn't supported here", option),
+      Self::NoFile => write!(f, "database isn't backed by a file"),
+      #[cfg(feature = "bincode")]
+      Self::BadDelta => write!(f, "delta doesn't fit the file"),
+      #[cfg(feature = "bincode")]
+      Self::Truncated => write!(f, "file is empty or truncated"),
+      #[cfg(feature = "bincode")]
+      Self::LoadFailed => write!(f, "database failed to load earlier"),
+      #[cfg(feature = "encryption")]
+      Self::Decrypt => write!(f, "couldn't decrypt file"),
+      #[cfg(feature = "signing")]
+      Self::BadSignature => write!(f, "file signature doesn't match"),
+      #[cfg(feature = "audit")]
+      Self::Tampered(line) => write!(f, "audit log has been tampered with at line {}", line),
+    }
+  }
+}
+
+impl std::error::Error for DataError {}
+
+impl From<io::Error> for DataError {
+  fn from(e: io::Error) -> Self {
+    Self::Io(e)
+  }
+}
+
+#[cfg(feature = "bincode")]
+impl From<bincode::Error> for DataError {
+  fn from(e: bincode::Error) -> Self {
+    Self::Bincode(e)
+  }
+}
+
+#[cfg(feature = "rkyv")]
+impl From<rkyv::rancor::Error> for DataError {
+  fn from(e: rkyv::rancor::Error) -> Self {
+    Self::Rkyv(e)
+  }
+}
+
+#[cfg(feature = "prost")]
+impl From<prost::DecodeError> for DataError {
+  fn from(e: prost::DecodeError) -> Self {
+    Self::Prost(e)
+  }
+}
+
+#[cfg(feature = "yaml")]
+impl From<serde_yaml::Error> for DataError {
+  fn from(e: serde_yaml::Error) -> Self {
+    Self::Yaml(e)
+  }
+}
+
+#[cfg(feature = "path")]
+impl From<serde_json::Error> for DataError {
+  fn from(e: serde_json::Error) -> Self {
+    Self::Json(e)
+  }
+}
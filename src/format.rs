@@ -0,0 +1,332 @@
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use std::time::SystemTime;
+#[cfg(feature = "encryption")]
+use std::time::Duration;
+use serde::{Serialize, Deserialize};
+#[cfg(feature = "encryption")]
+use crate::crypto::{Cipher, Secret};
+use crate::{DataError, Metadata};
+use crate::compression::{self, Compression};
+#[cfg(feature = "zstd")]
+use crate::compression::Dictionary;
+#[cfg(feature = "zstd")]
+use std::sync::Arc;
+#[cfg(feature = "signing")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "signing")]
+use sha2::Sha256;
+
+const MAGIC: &[u8; 8] = b"floppadb";
+const FORMAT: u8 = 1;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Header {
+  pub metadata: Metadata,
+  pub encryption: Option<Encryption>,
+  /// Whether the file ends with an HMAC-SHA256 of everything before it.
+  pub signed: bool,
+  /// The [`Compression::id`] of the payload.
+  pub compression: u8,
+  /// The length of the payload as written, before the signature.
+  pub length: u64,
+}
+
+/// How the payload is encrypted, kept in the header so it can be decrypted again.
+///
+/// The payload is encrypted with XChaCha20-Poly1305 under a random data key, which is stored
+/// wrapped by every key that can open the file.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Encryption {
+  pub keys: Vec<WrappedKey>,
+  pub nonce: [u8; 24],
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct WrappedKey {
+  /// Set when the key is derived from a password.
+  pub kdf: Option<Kdf>,
+  pub nonce: [u8; 24],
+  pub key: Vec<u8>,
+  /// When the key stops opening the file, set for the old key after a rotation.
+  pub expires: Option<SystemTime>,
+}
+
+/// Argon2id salt and params.
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Kdf {
+  pub salt: [u8; 16],
+  pub m_cost: u32,
+  pub t_cost: u32,
+  pub p_cost: u32,
+}
+
+/// Reads the header, or returns `None` if the data was written before headers existed.
+pub(crate) fn read_header<R: Read>(r: &mut R) -> Result<Option<Header>, DataError> {
+  let mut magic = [0; 9];
+  if r.read_exact(&mut magic).is_err() || &magic[..8] != MAGIC {
+    return Ok(None);
+  }
+  match magic[8] {
+    FORMAT => Ok(Some(bincode::deserialize_from(r)?)),
+    f => Err(DataError::UnknownFormat(f)),
+  }
+}
+
+/// What to do when opening a file whose signature doesn't verify, see [`crate::Builder::sign`].
+#[cfg(feature = "signing")]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnTamper {
+  /// Fail with [`DataError::BadSignature`].
+  Refuse,
+  /// Open it anyway and report it through [`crate::Database::tampered`].
+  Flag,
+}
+
+const TAG_LEN: usize = 32;
+
+/// Encodes and decodes the data along with its header.
+#[derive(Default, Clone)]
+pub(crate) struct Format {
+  pub compression: Compression,
+  /// Used with [`Compression::Zstd`], see [`crate::Builder::zstd_dictionary`].
+  #[cfg(feature = "zstd")]
+  pub dictionary: Option<Arc<Dictionary>>,
+  #[cfg(feature = "encryption")]
+  pub secret: Option<Secret>,
+  #[cfg(feature = "encryption")]
+  cipher: Option<Cipher>,
+  /// Opens unencrypted files despite a secret, see [`crate::Builder::allow_unencrypted`].
+  #[cfg(feature = "encryption")]
+  pub allow_unencrypted: bool,
+  #[cfg(feature = "signing")]
+  pub signing: Option<(Vec<u8>, OnTamper)>,
+  /// Set by [`Format::decode`] when the signature didn't verify.
+  #[cfg(feature = "signing")]
+  pub tampered: bool,
+  /// Writes the bare payload without a header, for files that other tools read.
+  pub raw: bool,
+}
+
+impl Format {
+  /// Prepares to write a file that didn't exist or was read by [`Format::decode`] with `existing`.
+  #[cfg_attr(not(feature = "encryption"), allow(unused_variables))]
+  pub fn init(&mut self, existing: Option<&Encryption>) -> Result<(), DataError> {
+    #[cfg(feature = "encryption")]
+    if let Some(secret) = &self.secret {
+      self.cipher = Some(Cipher::new(secret, existing)?);
+    }
+    Ok(())
+  }
+
+  /// Checks, decrypts and decompresses `bytes`, returning the serialized data and its metadata.
+  pub fn decode<'a>(
+    &mut self,
+    bytes: &'a [u8],
+  ) -> Result<(Cow<'a, [u8]>, Option<Metadata>), DataError> {
+    let mut payload = bytes;
+    let Some(header) = read_header(&mut payload)? else {
+      // a file without a header has no signature, so it must not slip past the check
+      #[cfg(feature = "signing")]
+      self.verify(bytes, false)?;
+      #[cfg(feature = "encryption")]
+      self.check_encrypted(false)?;
+      self.init(None)?;
+      return Ok((Cow::Borrowed(bytes), None));
+    };
+    // a cut short payload would otherwise fail to decrypt, verify or decompress
+    let expected = header.length + if header.signed { TAG_LEN as u64 } else { 0 };
+    if (payload.len() as u64) < expected {
+      return Err(DataError::Truncated);
+    }
+    // the header as written is authenticated by the encryption
+    #[cfg(feature = "encryption")]
+    let header_bytes = &bytes[MAGIC.len() + 1..bytes.len() - payload.len()];
+    self.init(header.encryption.as_ref())?;
+    if header.signed {
+      payload = &payload[..payload.len().saturating_sub(TAG_LEN)];
+    }
+    #[cfg(feature = "signing")]
+    self.verify(bytes, header.signed)?;
+    #[cfg(feature = "encryption")]
+    self.check_encrypted(header.encryption.is_some())?;
+    let payload = match &header.encryption {
+      None => self.decompress(header.compression, payload)?,
+      #[cfg(feature = "encryption")]
+      Some(e) => {
+        let cipher = self.cipher.as_ref().ok_or(DataError::Encrypted)?;
+        let payload = cipher.decrypt(e, header_bytes, payload)?;
+        match header.compression {
+          0 => Cow::Owned(payload),
+          c => Cow::Owned(self.decompress(c, &payload)?.into_owned()),
+        }
+      }
+      #[cfg(not(feature = "encryption"))]
+      Some(_) => return Err(DataError::Encrypted),
+    };
+    Ok((payload, Some(header.metadata)))
+  }
+
+  fn decompress<'a>(&self, id: u8, bytes: &'a [u8]) -> Result<Cow<'a, [u8]>, DataError> {
+    #[cfg(feature = "zstd")]
+    if id == compression::DICTIONARY {
+      let dictionary = self.dictionary.as_ref().ok_or(DataError::Dictionary)?;
+      return Ok(Cow::Owned(dictionary.decompress(bytes)?));
+    }
+    compression::decompress(id, bytes)
+  }
+
+  /// Compresses `payload`, returning the id of how it was compressed.
+  fn compress(&self, payload: Vec<u8>) -> Result<(u8, Vec<u8>), DataError> {
+    #[cfg(feature = "zstd")]
+    if let (Compression::Zstd(level), Some(dictionary)) = (self.compression, &self.dictionary) {
+      return Ok((
+        compression::DICTIONARY,
+        dictionary.compress(level, &payload)?,
+      ));
+    }
+    Ok((self.compression.id(), self.compression.compress(payload)?))
+  }
+
+  /// Refuses a file that isn't encrypted when there is a secret, since anyone who can write the
+  /// file could otherwise plant data that the next save encrypts.
+  #[cfg(feature = "encryption")]
+  fn check_encrypted(&self, encrypted: bool) -> Result<(), DataError> {
+    match self.secret {
+      Some(_) if !encrypted && !self.allow_unencrypted => Err(DataError::Unencrypted),
+      _ => Ok(()),
+    }
+  }
+
+  #[cfg(feature = "signing")]
+  fn verify(&mut self, bytes: &[u8], signed: bool) -> Result<(), DataError> {
+    let Some((key, on_tamper)) = &self.signing else {
+      return Ok(());
+    };
+    let (bytes, tag) = bytes.split_at(bytes.len().saturating_sub(TAG_LEN));
+    self.tampered |= !signed || mac(key, bytes).verify_slice(tag).is_err();
+    match on_tamper {
+      OnTamper::Refuse if self.tampered => Err(DataError::BadSignature),
+      _ => Ok(()),
+    }
+  }
+
+  /// Re-encrypts future writes with a new data key under `key`, letting the old key open the file
+  /// until `grace` has passed.
+  #[cfg(feature = "encryption")]
+  pub fn rotate_key(&mut self, key: [u8; 32], grace: Duration) -> Result<(), DataError> {
+    let secret = Secret::Key(key);
+    match &mut self.cipher {
+      Some(cipher) => cipher.rotate(&secret, grace)?,
+      None => self.cipher = Some(Cipher::new(&secret, None)?),
+    }
+    self.secret = Some(secret);
+    Ok(())
+  }
+
+  /// The inverse of [`Format::decode`].
+  pub fn encode(&self, payload: Vec<u8>, metadata: &Metadata) -> Result<Vec<u8>, DataError> {
+    if self.raw {
+      return Ok(payload);
+    }
+    let (compression, payload) = self.compress(payload)?;
+    #[cfg(feature = "encryption")]
+    let length = payload.len() + self.cipher.as_ref().map_or(0, |_| crate::crypto::OVERHEAD);
+    #[cfg(not(feature = "encryption"))]
+    let length = payload.len();
+    let header = Header {
+      metadata: metadata.clone(),
+      #[cfg(feature = "encryption")]
+      encryption: self.cipher.as_ref().map(Cipher::encryption),
+      #[cfg(not(feature = "encryption"))]
+      encryption: None,
+      #[cfg(feature = "signing")]
+      signed: self.signing.is_some(),
+      #[cfg(not(feature = "signing"))]
+      signed: false,
+      compression,
+      length: length as u64,
+    };
+    let header_bytes = bincode::serialize(&header)?;
+    let mut bytes = Vec::with_capacity(MAGIC.len() + 1 + header_bytes.len() + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.push(FORMAT);
+    bytes.extend_from_slice(&header_bytes);
+    match &header.encryption {
+      #[cfg(feature = "encryption")]
+      Some(e) => bytes.extend(
+        self
+          .cipher
+          .as_ref()
+          .unwrap()
+          .encrypt(e, &header_bytes, &payload),
+      ),
+      _ => bytes.extend(payload),
+    }
+    #[cfg(feature = "signing")]
+    if let Some((key, _)) = &self.signing {
+      let tag = mac(key, &bytes).finalize().into_bytes();
+      bytes.extend(tag);
+    }
+    Ok(bytes)
+  }
+}
+
+#[cfg(feature = "signing")]
+fn mac(key: &[u8], bytes: &[u8]) -> Hmac<Sha256> {
+  let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("hmac accepts any key length");
+  mac.update(bytes);
+  mac
+}
+
+/// Reads a database's metadata without loading its data, e.g. to check the app version first.
+///
+/// Returns `None` for files written before metadata was stored.
+pub fn read_metadata<P: AsRef<Path>>(path: P) -> Result<Option<Metadata>, DataError> {
+  Ok(read_header(&mut BufReader::new(File::open(path)?))?.map(|h| h.metadata))
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod test {
+  use super::*;
+
+  fn format(on_tamper: OnTamper) -> Format {
+    Format {
+      signing: Some((b"key".to_vec(), on_tamper)),
+      ..Default::default()
+    }
+  }
+
+  #[test]
+  fn test() {
+    let mut bytes = format(OnTamper::Refuse)
+      .encode(vec![1], &Metadata::new())
+      .unwrap();
+    assert_eq!(format(OnTamper::Refuse).decode(&bytes).unwrap().0, &[1][..]);
+    assert_eq!(Format::default().decode(&bytes).unwrap().0, &[1][..]);
+
+    let len = bytes.len();
+    bytes[len - TAG_LEN - 1] = 2;
+    assert!(matches!(
+      format(OnTamper::Refuse).decode(&bytes),
+      Err(DataError::BadSignature)
+    ));
+    let mut f = format(OnTamper::Flag);
+    assert_eq!(f.decode(&bytes).unwrap().0, &[2][..]);
+    assert!(f.tampered);
+  }
+
+  #[test]
+  fn unsigned() {
+    let raw = bincode::serialize(&vec![1u32]).unwrap();
+    assert!(matches!(
+      format(OnTamper::Refuse).decode(&raw),
+      Err(DataError::BadSignature)
+    ));
+    let mut f = format(OnTamper::Flag);
+    assert_eq!(f.decode(&raw).unwrap().0, &raw[..]);
+    assert!(f.tampered);
+  }
+}
@@ -0,0 +1,63 @@
+//! Warns about guards that are held for too long, see [`crate::Builder::warn_held`].
+
+use std::panic::Location;
+use std::time::{Duration, Instant};
+
+/// Times a guard from when it was acquired until it is dropped.
+pub(crate) struct Hold(Option<Timer>);
+
+struct Timer {
+  threshold: Duration,
+  start: Instant,
+  location: &'static Location<'static>,
+  write: bool,
+}
+
+impl Hold {
+  /// Starts timing a guard acquired by the caller's caller, if there is a `threshold`.
+  #[track_caller]
+  pub fn start(threshold: Option<Duration>, write: bool) -> Self {
+    Self(threshold.map(|threshold| Timer {
+      threshold,
+      start: Instant::now(),
+      location: Location::caller(),
+      write,
+    }))
+  }
+
+  fn warning(&self) -> Option<String> {
+    let t = self.0.as_ref()?;
+    let held = t.start.elapsed();
+    (held > t.threshold).then(|| {
+      format!(
+        "{} guard acquired at {} was held for {:?}",
+        if t.write { "write" } else { "read" },
+        t.location,
+        held
+      )
+    })
+  }
+}
+
+impl Drop for Hold {
+  fn drop(&mut self) {
+    if let Some(warning) = self.warning() {
+      log::warn!("{}", warning);
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let hold = Hold::start(Some(Duration::from_millis(5)), true);
+    assert_eq!(hold.warning(), None);
+    std::thread::sleep(Duration::from_millis(10));
+    let warning = hold.warning().unwrap();
+    assert!(warning.starts_with("write guard acquired at src/hold.rs:"));
+    assert_eq!(Hold::start(None, false).warning(), None);
+  }
+}
@@ -0,0 +1,151 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::time::{Duration, Instant};
+use crate::Database;
+
+/// What a subscriber's channel does when writes come in faster than it receives them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Coalesce {
+  /// Keep only the latest write.
+  Latest,
+  /// Keep every write, dropping the oldest once the channel is full.
+  Every,
+}
+
+/// A committed write, see [`Database::subscribe`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Notification {
+  /// The version after the write, see [`Database::version`].
+  pub version: u64,
+  /// The label the write was made with through [`Database::get_mut_labeled`].
+  pub label: Option<String>,
+}
+
+struct Channel {
+  state: Mutex<State>,
+  ready: Condvar,
+  capacity: usize,
+  coalesce: Coalesce,
+}
+
+#[derive(Default)]
+struct State {
+  queue: VecDeque<Notification>,
+  missed: u64,
+}
+
+/// Sends every write to the subscribers, without ever waiting for them.
+#[derive(Default)]
+pub(crate) struct Hub(Mutex<Vec<Weak<Channel>>>);
+
+impl Hub {
+  pub fn publish(&self, version: u64, label: Option<&str>) {
+    self.0.lock().unwrap().retain(|c| {
+      let Some(channel) = c.upgrade() else {
+        return false;
+      };
+      let mut state = channel.state.lock().unwrap();
+      let full = match channel.coalesce {
+        Coalesce::Latest => 1,
+        Coalesce::Every => channel.capacity,
+      };
+      while state.queue.len() >= full {
+        state.queue.pop_front();
+        state.missed += 1;
+      }
+      state.queue.push_back(Notification {
+        version,
+        label: label.map(str::to_string),
+      });
+      channel.ready.notify_all();
+      true
+    });
+  }
+}
+
+/// Receives notifications of writes, see [`Database::subscribe`].
+pub struct Subscriber(Arc<Channel>);
+
+impl Subscriber {
+  /// Waits for the next write.
+  pub fn recv(&self) -> Notification {
+    let state = self.0.state.lock().unwrap();
+    let mut state = self
+      .0
+      .ready
+      .wait_while(state, |s| s.queue.is_empty())
+      .unwrap();
+    state.queue.pop_front().unwrap()
+  }
+
+  /// Like [`Subscriber::recv`], but gives up after `timeout`.
+  pub fn recv_timeout(&self, timeout: Duration) -> Option<Notification> {
+    let deadline = Instant::now() + timeout;
+    let mut state = self.0.state.lock().unwrap();
+    while state.queue.is_empty() {
+      let left = deadline.saturating_duration_since(Instant::now());
+      if left.is_zero() {
+        return None;
+      }
+      state = self.0.ready.wait_timeout(state, left).unwrap().0;
+    }
+    state.queue.pop_front()
+  }
+
+  pub fn try_recv(&self) -> Option<Notification> {
+    self.0.state.lock().unwrap().queue.pop_front()
+  }
+
+  /// How many notifications were dropped or coalesced because this subscriber fell behind.
+  pub fn missed(&self) -> u64 {
+    self.0.state.lock().unwrap().missed
+  }
+}
+
+impl<T: Send + Sync + 'static> Database<T> {
+  /// Subscribes to writes through a channel holding up to `capacity` notifications.
+  ///
+  /// Writers never wait for subscribers, a subscriber that falls behind loses notifications
+  /// according to `coalesce` instead. The subscription ends when the [`Subscriber`] is dropped.
+  pub fn subscribe(&self, capacity: usize, coalesce: Coalesce) -> Subscriber {
+    let channel = Arc::new(Channel {
+      state: Mutex::default(),
+      ready: Condvar::new(),
+      capacity: capacity.max(1),
+      coalesce,
+    });
+    let inner = self.0.read().unwrap();
+    inner.hub.0.lock().unwrap().push(Arc::downgrade(&channel));
+    Subscriber(channel)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let db = Database::new_custom(0, |_| {});
+    let every = db.subscribe(2, Coalesce::Every);
+    let latest = db.subscribe(2, Coalesce::Latest);
+    for _ in 0..3 {
+      *db.get_mut() += 1;
+    }
+    *db.get_mut_labeled("reset") = 0;
+    assert_eq!(every.try_recv().map(|n| n.version), Some(3));
+    assert_eq!(every.recv().label.as_deref(), Some("reset"));
+    assert_eq!(every.missed(), 2);
+    assert_eq!(latest.try_recv().map(|n| n.version), Some(4));
+    assert_eq!(latest.try_recv(), None);
+    assert_eq!(latest.missed(), 3);
+
+    drop(latest);
+    *db.get_mut() += 1;
+    assert_eq!(db.0.read().unwrap().hub.0.lock().unwrap().len(), 1);
+    assert_eq!(
+      every.recv_timeout(Duration::from_secs(1)).unwrap().version,
+      5
+    );
+  }
+}
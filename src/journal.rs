@@ -0,0 +1,263 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::Arc;
+use serde::{Serialize, Deserialize, de::DeserializeOwned};
+use crate::{Database, DataError};
+
+/// A vector clock, counting the writes seen from each replica.
+///
+/// Clocks are only partially ordered, two clocks where neither is ahead are concurrent.
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Eq, Debug)]
+pub struct VectorClock(BTreeMap<String, u64>);
+
+impl VectorClock {
+  /// The number of writes seen from `replica`.
+  pub fn get(&self, replica: &str) -> u64 {
+    self.0.get(replica).copied().unwrap_or(0)
+  }
+
+  fn tick(&mut self, replica: &str) {
+    *self.0.entry(replica.to_string()).or_default() += 1;
+  }
+
+  fn merge(&mut self, other: &VectorClock) {
+    for (replica, n) in &other.0 {
+      let m = self.0.entry(replica.clone()).or_default();
+      *m = (*m).max(*n);
+    }
+  }
+}
+
+impl PartialOrd for VectorClock {
+  fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+    let (mut less, mut greater) = (false, false);
+    for replica in self.0.keys().chain(other.0.keys()) {
+      match self.get(replica).cmp(&other.get(replica)) {
+        Ordering::Less => less = true,
+        Ordering::Greater => greater = true,
+        Ordering::Equal => {}
+      }
+    }
+    match (less, greater) {
+      (false, false) => Some(Ordering::Equal),
+      (true, false) => Some(Ordering::Less),
+      (false, true) => Some(Ordering::Greater),
+      (true, true) => None,
+    }
+  }
+}
+
+/// The state of a replica after one of its writes, see [`SyncedDatabase::pending_changes_since`].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Change<T> {
+  pub replica: String,
+  pub clock: VectorClock,
+  pub data: T,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "T: Serialize", deserialize = "T: Deserialize<'de>"))]
+struct Journal<T> {
+  /// Every write seen so far, both local and remote.
+  clock: VectorClock,
+  /// The latest change from each replica, as each one supersedes the earlier ones.
+  changes: BTreeMap<String, Change<T>>,
+}
+
+impl<T> Default for Journal<T> {
+  fn default() -> Self {
+    Self {
+      clock: VectorClock::default(),
+      changes: BTreeMap::new(),
+    }
+  }
+}
+
+/// A database whose writes are journaled with vector clocks, see [`Database::sync`].
+///
+/// Replicas exchange changes however they like, e.g. through a file sync service, and apply
+/// each other's with [`SyncedDatabase::apply_remote`].
+pub struct SyncedDatabase<T> {
+  db: Database<T>,
+  journal: Database<Journal<T>>,
+  replica: String,
+  /// Set while applying remote changes, so they aren't journaled as local writes.
+  applying: Arc<AtomicBool>,
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static> Database<T> {
+  /// Journals every write as a change from `replica` in a journal at `path`, for offline-first
+  /// clients that sync with each other.
+  ///
+  /// Each change holds the whole data, so this suits small data. Only the latest change from
+  /// each replica is kept.
+  pub fn sync<P: AsRef<Path>>(
+    &self,
+    path: P,
+    replica: &str,
+  ) -> Result<SyncedDatabase<T>, DataError> {
+    let journal = Database::<Journal<T>>::builder().open(path)?;
+    let applying = Arc::new(AtomicBool::new(false));
+    // journaling stops once the handle and with it the journal is dropped
+    let j = Arc::downgrade(&journal.0);
+    let (a, r) = (applying.clone(), replica.to_string());
+    self
+      .0
+      .read()
+      .unwrap()
+      .watchers
+      .lock()
+      .unwrap()
+      .push(Box::new(move |data: &T| {
+        let Some(j) = j.upgrade() else {
+          return false;
+        };
+        if !a.swap(false, atomic::Ordering::Relaxed) {
+          Database(j).get_mut().record(&r, data.clone());
+        }
+        true
+      }));
+    Ok(SyncedDatabase {
+      db: self.clone(),
+      journal,
+      replica: replica.to_string(),
+      applying,
+    })
+  }
+}
+
+impl<T> Journal<T> {
+  /// Records a new local write.
+  fn record(&mut self, replica: &str, data: T) {
+    self.clock.tick(replica);
+    let change = Change {
+      replica: replica.to_string(),
+      clock: self.clock.clone(),
+      data,
+    };
+    self.changes.insert(replica.to_string(), change);
+  }
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static> SyncedDatabase<T> {
+  /// Every write this replica has seen, local or remote.
+  pub fn clock(&self) -> VectorClock {
+    self.journal.get().clock.clone()
+  }
+
+  /// The changes that a replica at `clock` hasn't seen yet, including those from other replicas.
+  pub fn pending_changes_since(&self, clock: &VectorClock) -> Vec<Change<T>> {
+    let journal = self.journal.get();
+    journal
+      .changes
+      .values()
+      .filter(|c| matches!(c.clock.partial_cmp(clock), Some(Ordering::Greater) | None))
+      .cloned()
+      .collect()
+  }
+
+  /// Applies changes from other replicas.
+  ///
+  /// A change that is ahead of this replica replaces the data, and one that was already seen is
+  /// skipped. A change that is concurrent with this replica's writes is a conflict, which
+  /// `resolver` settles by merging the local and remote data into a new local write.
+  pub fn apply_remote<R: Fn(&T, &T) -> T>(&self, changes: Vec<Change<T>>, resolver: R) {
+    for change in changes {
+      if change.clock <= self.journal.get().clock {
+        continue;
+      }
+      // the watcher locks the journal while the data is locked, so lock in the same order
+      let mut data = self.db.get_mut();
+      let mut journal = self.journal.get_mut();
+      match change.clock.partial_cmp(&journal.clock) {
+        Some(Ordering::Less | Ordering::Equal) => {}
+        Some(Ordering::Greater) => {
+          *data = change.data.clone();
+          journal.clock.merge(&change.clock);
+        }
+        None => {
+          *data = resolver(&data, &change.data);
+          journal.clock.merge(&change.clock);
+          journal.record(&self.replica, data.clone());
+        }
+      }
+      let newer = journal
+        .changes
+        .get(&change.replica)
+        .is_none_or(|c| c.clock < change.clock);
+      if newer && change.replica != self.replica {
+        journal.changes.insert(change.replica.clone(), change);
+      }
+      self.applying.store(true, atomic::Ordering::Relaxed);
+    }
+  }
+}
+
+impl<T> Deref for SyncedDatabase<T> {
+  type Target = Database<T>;
+
+  fn deref(&self) -> &Database<T> {
+    &self.db
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::fs;
+  use super::*;
+
+  fn open(replica: &str) -> SyncedDatabase<Vec<u32>> {
+    let path = std::env::temp_dir().join(format!("floppadb-journal-{replica}.db"));
+    let _ = fs::remove_file(&path);
+    Database::new_custom(vec![], |_| {})
+      .sync(path, replica)
+      .unwrap()
+  }
+
+  #[test]
+  fn test() {
+    let (a, b) = (open("a"), open("b"));
+    a.get_mut().push(1);
+    b.apply_remote(a.pending_changes_since(&b.clock()), |_, r| r.clone());
+    assert_eq!(*b.get(), [1]);
+    assert!(a.pending_changes_since(&b.clock()).is_empty());
+
+    a.get_mut().push(2);
+    b.get_mut().push(3);
+    let merge = |l: &Vec<u32>, r: &Vec<u32>| {
+      let mut v = l.clone();
+      v.extend(r.iter().filter(|x| !l.contains(x)));
+      v.sort();
+      v
+    };
+    let (from_a, from_b) = (
+      a.pending_changes_since(&b.clock()),
+      b.pending_changes_since(&a.clock()),
+    );
+    a.apply_remote(from_b, merge);
+    b.apply_remote(from_a, merge);
+    assert_eq!(*a.get(), [1, 2, 3]);
+    assert_eq!(*b.get(), [1, 2, 3]);
+
+    b.apply_remote(a.pending_changes_since(&b.clock()), merge);
+    a.apply_remote(b.pending_changes_since(&a.clock()), merge);
+    assert_eq!(a.clock(), b.clock());
+  }
+
+  #[test]
+  fn dropped() {
+    let a = open("dropped");
+    let db = Database::clone(&a);
+    a.get_mut().push(1);
+    a.journal.flush().unwrap();
+    drop(a);
+    db.get_mut().push(2);
+    assert!(db.0.read().unwrap().watchers.lock().unwrap().is_empty());
+    let path = std::env::temp_dir().join("floppadb-journal-dropped.db");
+    let journal = Database::<Journal<Vec<u32>>>::new(path).unwrap();
+    assert_eq!(journal.get().clock.get("dropped"), 1);
+  }
+}
@@ -0,0 +1,128 @@
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{Builder, Database, DataError, ReadGuard, WriteGuard};
+
+/// A database that is only loaded when it is first accessed, see [`Builder::open_lazy`].
+pub struct LazyDatabase<T>(Arc<Lazy<T>>);
+
+struct Lazy<T> {
+  path: PathBuf,
+  /// Taken by the first load.
+  builder: Mutex<Option<Builder<T>>>,
+  db: OnceLock<Database<T>>,
+}
+
+impl<T> Clone for LazyDatabase<T> {
+  fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Send + Sync + 'static> Builder<T> {
+  /// Like [`Builder::open`], but the file is only read and deserialized when the database is
+  /// first accessed.
+  ///
+  /// Only checks that the file can be stat'ed, a missing file is fine.
+  pub fn open_lazy<P: AsRef<Path>>(self, path: P) -> Result<LazyDatabase<T>, DataError> {
+    let path = path.as_ref().to_path_buf();
+    match fs::metadata(&path) {
+      Err(e) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+      _ => {}
+    }
+    Ok(LazyDatabase(Arc::new(Lazy {
+      path,
+      builder: Mutex::new(Some(self)),
+      db: OnceLock::new(),
+    })))
+  }
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Send + Sync + 'static> LazyDatabase<T> {
+  /// Loads the database if it isn't loaded yet.
+  ///
+  /// Once loading has failed, it isn't attempted again and this returns [`DataError::LoadFailed`].
+  pub fn load(&self) -> Result<&Database<T>, DataError> {
+    if let Some(db) = self.0.db.get() {
+      return Ok(db);
+    }
+    let mut builder = self.0.builder.lock().unwrap();
+    if let Some(db) = self.0.db.get() {
+      return Ok(db);
+    }
+    let db = builder
+      .take()
+      .ok_or(DataError::LoadFailed)?
+      .open(&self.0.path)?;
+    Ok(self.0.db.get_or_init(|| db))
+  }
+
+  /// Starts loading the database on a background thread.
+  pub fn preload(&self) {
+    let db = self.clone();
+    thread::spawn(move || {
+      let _ = db.load();
+    });
+  }
+
+  pub fn is_loaded(&self) -> bool {
+    self.0.db.get().is_some()
+  }
+
+  guard_fn! {
+    /// Loads the database if needed and locks it for reading.
+    ///
+    /// Panics if it can't be loaded, use [`LazyDatabase::load`] to handle the error.
+    #[track_caller]
+    fn get(&self) -> ReadGuard<'_, T> {
+      self.load().unwrap().get()
+    }
+  }
+
+  guard_fn! {
+    /// Loads the database if needed and locks it for writing, see [`LazyDatabase::get`].
+    #[track_caller]
+    fn get_mut(&self) -> WriteGuard<'_, T> {
+      self.load().unwrap().get_mut()
+    }
+  }
+
+  /// Loads the database if needed and calls `f` with it locked for reading, see
+  /// [`Database::with_read`].
+  #[track_caller]
+  pub fn with_read<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+    self.load().unwrap().with_read(f)
+  }
+
+  /// Loads the database if needed and calls `f` with it locked for writing.
+  #[track_caller]
+  pub fn with_write<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+    self.load().unwrap().with_write(f)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-lazy.db");
+    let _ = fs::remove_file(&path);
+    let db = Database::<Vec<u32>>::builder()
+      .manual_save()
+      .open(&path)
+      .unwrap();
+    db.get_mut().push(1);
+    db.close().unwrap();
+
+    let db = Database::<Vec<u32>>::open_lazy(&path).unwrap();
+    assert!(!db.is_loaded());
+    db.preload();
+    assert_eq!(db.get()[..], [1]);
+    assert!(db.is_loaded());
+  }
+}
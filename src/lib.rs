@@ -1,64 +1,505 @@
-#![feature(pointer_byte_offsets)]
-use std::thread;
-use std::mem::size_of;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
-use std::cell::UnsafeCell;
+use std::mem;
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::ops::{Deref, DerefMut};
-use std::fs::File;
+use std::time::Instant;
+#[cfg(feature = "encryption")]
+use std::time::Duration;
+#[cfg(feature = "bincode")]
 use std::path::Path;
+#[cfg(feature = "bincode")]
 use serde::{Serialize, de::DeserializeOwned};
 
-#[derive(Clone)]
+/// Declares a method that returns a guard, which is only public without the `strict` feature.
+macro_rules! guard_fn {
+  ($(#[$m:meta])* fn $($rest:tt)*) => {
+    $(#[$m])*
+    #[cfg(not(feature = "strict"))]
+    pub fn $($rest)*
+
+    $(#[$m])*
+    #[cfg(feature = "strict")]
+    #[allow(dead_code)]
+    pub(crate) fn $($rest)*
+  };
+}
+
+#[cfg(feature = "tokio")]
+mod actor;
+#[cfg(feature = "bincode")]
+mod append;
+#[cfg(feature = "rkyv")]
+mod archived;
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(feature = "bincode")]
+mod backup;
+#[cfg(feature = "borsh")]
+mod borsh;
+mod builder;
+#[cfg(feature = "bincode")]
+mod canonical;
+mod collection;
+#[cfg(feature = "bincode")]
+mod compression;
+mod cow;
+#[cfg(feature = "encryption")]
+mod crypto;
+mod deadlock;
+#[cfg(feature = "bincode")]
+mod delta;
+#[cfg(feature = "bincode")]
+mod disk;
+mod entry;
+mod error;
+#[cfg(feature = "bincode")]
+mod format;
+mod hold;
+mod hub;
+#[cfg(feature = "bincode")]
+mod journal;
+#[cfg(feature = "bincode")]
+mod lazy;
+mod metadata;
+#[cfg(feature = "path")]
+mod path;
+#[cfg(feature = "bincode")]
+mod profile;
+#[cfg(feature = "prost")]
+mod prost;
+mod registry;
+mod replica;
+mod saver;
+#[cfg(feature = "bincode")]
+mod set;
+#[cfg(feature = "bincode")]
+mod store;
+pub mod time;
+#[cfg(feature = "tokio")]
+mod runtime;
+#[cfg(feature = "testing")]
+pub mod testing;
+mod watch;
+#[cfg(feature = "yaml")]
+mod yaml;
+
+#[cfg(feature = "tokio")]
+pub use actor::DatabaseActor;
+#[cfg(feature = "bincode")]
+pub use append::{AppendGuard, AppendLog};
+#[cfg(feature = "rkyv")]
+pub use archived::{ArchivedDatabase, ArchivedGuard};
+#[cfg(feature = "audit")]
+pub use audit::verify_audit_log;
+#[cfg(feature = "bincode")]
+pub use backup::{Backups, Retention};
+pub use builder::Builder;
+pub use collection::{Collection, Eviction};
+#[cfg(feature = "bincode")]
+pub use compression::Compression;
+#[cfg(feature = "zstd")]
+pub use compression::Dictionary;
+#[cfg(feature = "bincode")]
+pub use disk::{OnTruncated, OpenOptions};
+pub use entry::EntryGuard;
+pub use error::DataError;
+#[cfg(feature = "bincode")]
+pub use format::read_metadata;
+#[cfg(feature = "signing")]
+pub use format::OnTamper;
+pub use hub::{Coalesce, Notification, Subscriber};
+#[cfg(feature = "bincode")]
+pub use journal::{Change, SyncedDatabase, VectorClock};
+#[cfg(feature = "bincode")]
+pub use lazy::LazyDatabase;
+pub use metadata::Metadata;
+#[cfg(feature = "bincode")]
+pub use profile::SaveProfile;
+pub use registry::{flush_all, open_databases, OpenDatabase};
+pub use replica::Replica;
+pub use saver::{DeadSaver, SavePolicy, Saved, SaverPanic, SaverStatus};
+#[cfg(feature = "bincode")]
+pub use set::DatabaseSet;
+#[cfg(feature = "bincode")]
+pub use store::Store;
+#[cfg(feature = "tokio")]
+pub use runtime::ChangeEvent;
+
 pub struct Database<T>(Arc<RwLock<Inner<T>>>);
 
+impl<T> Clone for Database<T> {
+  fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
 #[cfg(feature = "bincode")]
 impl<T: Serialize + DeserializeOwned + Default + Send + Sync + 'static> Database<T> {
-  pub fn new<P: AsRef<Path> + Clone + Send + 'static>(path: P) -> Result<Self, bincode::Error> {
-    Ok(Self::new_custom(
-      match File::open(path.clone()) {
-        Ok(f) => bincode::deserialize_from(f)?,
-        Err(_) => T::default(),
-      },
-      move |data| bincode::serialize_into(File::create(path.clone()).unwrap(), data).unwrap(),
-    ))
+  pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, DataError> {
+    Self::builder().open(path)
+  }
+
+  /// Opens a database that is only loaded when it is first accessed, see [`Builder::open_lazy`].
+  pub fn open_lazy<P: AsRef<Path>>(path: P) -> Result<LazyDatabase<T>, DataError> {
+    Self::builder().open_lazy(path)
+  }
+
+  /// Opens a database encrypted with a key derived from `password`, see [`Builder::password`].
+  #[cfg(feature = "encryption")]
+  pub fn new_with_password<P: AsRef<Path>>(path: P, password: &str) -> Result<Self, DataError> {
+    Self::builder().password(password).open(path)
   }
 }
 
-impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> Database<T> {
+impl<T: Send + Sync + 'static> Database<T> {
+  pub fn builder() -> Builder<T> {
+    Builder::new()
+  }
+
   pub fn new_custom<S: Fn(&T) + Send + 'static>(data: T, save: S) -> Self {
-    let db = Arc::new(RwLock::new(Inner { dirty: false, data }));
-    let d = db.clone();
-    thread::spawn(move || loop {
-      let r = unsafe {
-        &mut *UnsafeCell::<Inner<T>>::raw_get(
-          Arc::as_ptr(&db)
-            .byte_add(size_of::<RwLock<Inner<T>>>() - size_of::<UnsafeCell<Inner<T>>>())
-            as _,
-        )
-      };
-      if r.dirty {
-        save(&r.data);
-        r.dirty = false
+    Self::builder().build(data, save).unwrap()
+  }
+
+  fn spawn<S: Fn(&T, &Metadata) -> Result<Option<usize>, DataError> + Send + 'static>(
+    data: T,
+    metadata: Metadata,
+    save: S,
+    mut builder: Builder<T>,
+  ) -> Result<Self, DataError> {
+    let options = mem::take(&mut builder.saver);
+    let db = Self::create(data, metadata, Box::new(save), builder)?;
+    if options.policy == SavePolicy::Manual {
+      return Ok(db);
+    }
+    saver::spawn(db.0.clone(), options)?;
+    Ok(db)
+  }
+
+  /// Creates the database without starting anything to save it.
+  fn create(
+    data: T,
+    metadata: Metadata,
+    save: Save<T>,
+    builder: Builder<T>,
+  ) -> Result<Self, DataError> {
+    builder.check_loaded(&metadata)?;
+    #[cfg(feature = "audit")]
+    let (audit, version) = match builder.audit_log {
+      Some(path) => {
+        let (log, version) = audit::AuditLog::open(&path)?;
+        (Some(Mutex::new(log)), version)
       }
-    });
-    Self(d)
+      None => (None, 0),
+    };
+    #[cfg(not(feature = "audit"))]
+    let version = 0;
+    let register = builder.register;
+    let db = Self(Arc::new(RwLock::new(Inner {
+      dirty: AtomicBool::new(false),
+      version: AtomicU64::new(version),
+      #[cfg(feature = "audit")]
+      audit,
+      metadata: Mutex::new(metadata.clone()),
+      loaded: metadata,
+      app_version: builder.app_version,
+      #[cfg(feature = "bincode")]
+      disk: builder.disk,
+      #[cfg(feature = "bincode")]
+      encode: builder.encode,
+      #[cfg(feature = "tokio")]
+      notify: runtime::Notify(Arc::new(tokio::sync::Notify::new())),
+      #[cfg(feature = "tokio")]
+      saved: tokio::sync::watch::Sender::new(version),
+      #[cfg(feature = "tokio")]
+      changes: tokio::sync::broadcast::Sender::new(64),
+      watchers: Mutex::new(vec![]),
+      hub: hub::Hub::default(),
+      warn_held: builder.warn_held,
+      clock: builder.clock,
+      writes: time::WriteSignal::default(),
+      save: Mutex::new(save),
+      health: Arc::default(),
+      on_dead_saver: builder.on_dead_saver,
+      on_saved: builder.on_saved,
+      data,
+    })));
+    if register {
+      registry::register(&db);
+    }
+    Ok(db)
+  }
+
+  guard_fn! {
+    #[track_caller]
+    fn get(&self) -> ReadGuard<'_, T> {
+      let held = deadlock::acquire(&*self.0, false);
+      let inner = self.0.read().unwrap();
+      let hold = hold::Hold::start(inner.warn_held, false);
+      ReadGuard(inner, held, hold)
+    }
+  }
+
+  guard_fn! {
+    #[track_caller]
+    fn get_mut(&self) -> WriteGuard<'_, T> {
+      self.write(None)
+    }
+  }
+
+  guard_fn! {
+    /// Like [`Database::get_mut`], but the write is recorded in the audit log with `label`.
+    #[track_caller]
+    fn get_mut_labeled(&self, label: &str) -> WriteGuard<'_, T> {
+      self.write(Some(label.to_string()))
+    }
   }
 
-  pub fn get(&self) -> ReadGuard<T> {
-    ReadGuard(self.0.read().unwrap())
+  /// Calls `f` with the data locked for reading.
+  ///
+  /// With the `strict` feature this is the only way to read the data, so a guard can't be kept
+  /// around by accident.
+  #[track_caller]
+  pub fn with_read<R, F: FnOnce(&T) -> R>(&self, f: F) -> R {
+    f(&self.get())
   }
 
-  pub fn get_mut(&self) -> WriteGuard<T> {
-    WriteGuard(self.0.write().unwrap())
+  /// Calls `f` with the data locked for writing, see [`Database::with_read`].
+  #[track_caller]
+  pub fn with_write<R, F: FnOnce(&mut T) -> R>(&self, f: F) -> R {
+    f(&mut self.get_mut())
+  }
+
+  #[track_caller]
+  fn write(&self, label: Option<String>) -> WriteGuard<'_, T> {
+    let held = deadlock::acquire(&*self.0, true);
+    let inner = self.0.write().unwrap();
+    if inner.health.check(inner.on_dead_saver) {
+      drop(inner);
+      panic!("the saver has stopped, writes are no longer saved");
+    }
+    let hold = hold::Hold::start(inner.warn_held, true);
+    WriteGuard(inner, label, held, hold, true)
+  }
+
+  /// Whether the saver is still running, and when it last ran and failed.
+  pub fn saver_health(&self) -> SaverStatus {
+    self.0.read().unwrap().health.status()
+  }
+
+  /// The number of writes committed so far, continuing from the audit log if there is one.
+  pub fn version(&self) -> u64 {
+    self.0.read().unwrap().version.load(Ordering::Relaxed)
+  }
+
+  /// Saves the data now if it changed since the last save.
+  ///
+  /// This is the only way a database with [`SavePolicy::Manual`] is saved.
+  pub fn flush(&self) -> Result<(), DataError> {
+    self.0.read().unwrap().save()
+  }
+
+  /// Flushes the database and drops this handle.
+  pub fn close(self) -> Result<(), DataError> {
+    self.flush()
+  }
+
+  /// The metadata as of the last save. [`Builder::app_version`] is only stamped once this process
+  /// saves, until then it is the version that saved the file.
+  pub fn metadata(&self) -> Metadata {
+    self.0.read().unwrap().metadata.lock().unwrap().clone()
+  }
+
+  /// The metadata as it was read when opening, before this process saved anything.
+  pub fn loaded_metadata(&self) -> Metadata {
+    self.0.read().unwrap().loaded.clone()
+  }
+
+  /// Encodes the data exactly like it is saved to the file, see [`Builder::from_bytes`].
+  ///
+  /// Databases that aren't backed by a file use the default format.
+  #[cfg(feature = "bincode")]
+  pub fn to_bytes(&self) -> Result<Vec<u8>, DataError>
+  where
+    T: Serialize,
+  {
+    let inner = self.0.read().unwrap();
+    let mut metadata = inner.metadata.lock().unwrap().clone();
+    match &inner.disk {
+      Some(disk) => {
+        if disk.canonical {
+          metadata.modified = metadata.created;
+        }
+        let payload = inner.payload(disk)?;
+        disk.format.lock().unwrap().encode(payload, &metadata)
+      }
+      None => format::Format::default().encode(bincode::serialize(&inner.data)?, &metadata),
+    }
+  }
+
+  /// What the saves so far cost, if [`Builder::profile_saves`] was set.
+  #[cfg(feature = "bincode")]
+  pub fn save_profile(&self) -> Option<SaveProfile> {
+    let inner = self.0.read().unwrap();
+    inner.disk.as_ref()?.profiler.as_ref().map(|p| p.total())
+  }
+
+  /// Whether the file's signature didn't verify when it was opened with [`OnTamper::Flag`].
+  #[cfg(feature = "signing")]
+  pub fn tampered(&self) -> bool {
+    let inner = self.0.read().unwrap();
+    inner
+      .disk
+      .as_ref()
+      .is_some_and(|d| d.format.lock().unwrap().tampered)
+  }
+
+  /// Atomically re-encrypts the file under `key` with a new data key.
+  ///
+  /// Until `grace` has passed the previous key can still open the file, so other processes can
+  /// switch over to the new key in their own time.
+  #[cfg(feature = "encryption")]
+  pub fn rotate_key(&self, key: [u8; 32], grace: Duration) -> Result<(), DataError>
+  where
+    T: Serialize,
+  {
+    let inner = self.0.read().unwrap();
+    let disk = inner.disk.as_ref().ok_or(DataError::NoFile)?;
+    let mut format = disk.format.lock().unwrap();
+    let mut rotated = format.clone();
+    rotated.rotate_key(key, grace)?;
+    let metadata = inner.stamp();
+    disk.snapshot(&rotated, inner.payload(disk)?, &metadata)?;
+    *format = rotated;
+    Ok(())
   }
 }
 
 struct Inner<T> {
-  dirty: bool,
+  dirty: AtomicBool,
+  version: AtomicU64,
+  #[cfg(feature = "audit")]
+  audit: Option<Mutex<audit::AuditLog>>,
+  metadata: Mutex<Metadata>,
+  /// The metadata when opening, see [`Database::loaded_metadata`].
+  loaded: Metadata,
+  /// Stamped into the metadata on every save, see [`Builder::app_version`].
+  app_version: Option<String>,
+  #[cfg(feature = "bincode")]
+  disk: Option<Arc<disk::Disk>>,
+  /// Serializes the payload instead of bincode, set when opened with another encoding.
+  #[cfg(feature = "bincode")]
+  encode: Option<builder::Encode<T>>,
+  /// Wakes the saver task of a database opened with [`Builder::open_async`].
+  #[cfg(feature = "tokio")]
+  notify: runtime::Notify,
+  /// The last version that was saved.
+  #[cfg(feature = "tokio")]
+  saved: tokio::sync::watch::Sender<u64>,
+  #[cfg(feature = "tokio")]
+  changes: tokio::sync::broadcast::Sender<runtime::ChangeEvent>,
+  watchers: Mutex<Vec<watch::Watcher<T>>>,
+  hub: hub::Hub,
+  /// See [`Builder::warn_held`].
+  warn_held: Option<std::time::Duration>,
+  clock: Arc<dyn time::Clock>,
+  /// Wakes the saver after a write.
+  writes: time::WriteSignal,
+  save: Mutex<Save<T>>,
+  health: Arc<saver::Health>,
+  on_dead_saver: Option<DeadSaver>,
+  on_saved: Option<saver::OnSaved>,
   data: T,
 }
 
-pub struct ReadGuard<'a, T>(RwLockReadGuard<'a, Inner<T>>);
+/// Saves the data, returning how many bytes were written if it is known.
+type Save<T> = Box<dyn Fn(&T, &Metadata) -> Result<Option<usize>, DataError> + Send>;
+
+impl<T> Inner<T> {
+  /// Updates the metadata for a save that is about to happen, returning it.
+  fn stamp(&self) -> Metadata {
+    let mut m = self.metadata.lock().unwrap();
+    m.modified = self.clock.now();
+    if self.app_version.is_some() {
+      m.app_version.clone_from(&self.app_version);
+    }
+    m.clone()
+  }
+
+  /// Serializes the data like the saver does, with the database's own encoding.
+  #[cfg(feature = "bincode")]
+  fn payload(&self, disk: &disk::Disk) -> Result<Vec<u8>, DataError>
+  where
+    T: Serialize,
+  {
+    match &self.encode {
+      Some(encode) => encode(&self.data),
+      None => disk.serialize(&self.data),
+    }
+  }
+
+  /// Saves the data if it changed since the last save.
+  fn save(&self) -> Result<(), DataError> {
+    // held until the save is done, so a flush waits for a save in progress and retries it if it
+    // failed instead of seeing clean data
+    let save = self.save.lock().unwrap();
+    if !self.dirty.swap(false, Ordering::Relaxed) {
+      return Ok(());
+    }
+    let metadata = self.stamp();
+    let version = self.version.load(Ordering::Relaxed);
+    let start = Instant::now();
+    let bytes = match save(&self.data, &metadata) {
+      Ok(bytes) => bytes,
+      Err(e) => {
+        self.dirty.store(true, Ordering::Relaxed);
+        return Err(e);
+      }
+    };
+    self.saved(Saved {
+      version,
+      bytes,
+      duration: start.elapsed(),
+    });
+    Ok(())
+  }
+
+  fn saved(&self, saved: Saved) {
+    #[cfg(feature = "tokio")]
+    self.saved.send_replace(saved.version);
+    if let Some(f) = &self.on_saved {
+      f(&saved);
+    }
+  }
+
+  fn commit(&self, label: Option<&str>) {
+    let version = self.version.fetch_add(1, Ordering::Relaxed) + 1;
+    #[cfg(feature = "audit")]
+    if let Some(log) = &self.audit {
+      // kept by the log and retried with the next write
+      if let Err(e) = log.lock().unwrap().record(version, label, self.clock.now()) {
+        self.health.error(&format!("audit log: {}", e));
+      }
+    }
+    self.dirty.store(true, Ordering::Relaxed);
+    self.watchers.lock().unwrap().retain_mut(|w| w(&self.data));
+    self.hub.publish(version, label);
+    self.writes.notify();
+    #[cfg(feature = "tokio")]
+    {
+      self.notify.0.notify_one();
+      let _ = self.changes.send(runtime::ChangeEvent {
+        version,
+        label: label.map(str::to_string),
+      });
+    }
+  }
+}
+
+pub struct ReadGuard<'a, T>(
+  RwLockReadGuard<'a, Inner<T>>,
+  #[allow(dead_code)] deadlock::Held,
+  #[allow(dead_code)] hold::Hold,
+);
 
 impl<T> Deref for ReadGuard<'_, T> {
   type Target = T;
@@ -76,7 +517,14 @@ impl<T> Deref for WriteGuard<'_, T> {
   }
 }
 
-pub struct WriteGuard<'a, T>(RwLockWriteGuard<'a, Inner<T>>);
+pub struct WriteGuard<'a, T>(
+  RwLockWriteGuard<'a, Inner<T>>,
+  Option<String>,
+  #[allow(dead_code)] deadlock::Held,
+  #[allow(dead_code)] hold::Hold,
+  /// Cleared by [`Database::try_update`] when the write was rolled back.
+  bool,
+);
 
 impl<T> DerefMut for WriteGuard<'_, T> {
   fn deref_mut(&mut self) -> &mut T {
@@ -86,7 +534,9 @@ impl<T> DerefMut for WriteGuard<'_, T> {
 
 impl<T> Drop for WriteGuard<'_, T> {
   fn drop(&mut self) {
-    self.0.dirty = true;
+    if self.4 {
+      self.0.commit(self.1.as_deref());
+    }
   }
 }
 
@@ -105,5 +555,76 @@ mod test {
     let db = Database::<Test>::new("test.db").unwrap();
     println!("{}", db.get().a);
     db.get_mut().a = 3;
+    db.with_write(|t| t.a += 1);
+    assert_eq!(db.with_read(|t| t.a), 4);
+  }
+
+  #[test]
+  fn metadata() {
+    let path = std::env::temp_dir().join("floppadb-metadata.db");
+    let _ = std::fs::remove_file(&path);
+    let db = Database::<Test>::builder()
+      .app_version("1.0")
+      .open(&path)
+      .unwrap();
+    db.get_mut().a = 1;
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+    let metadata = loop {
+      if let Ok(Some(m)) = read_metadata(&path) {
+        break m;
+      }
+      assert!(std::time::Instant::now() < deadline, "never saved");
+      std::thread::sleep(std::time::Duration::from_millis(1));
+    };
+    assert_eq!(metadata.app_version.as_deref(), Some("1.0"));
+    assert_eq!(metadata.created, db.metadata().created);
+    db.close().unwrap();
+
+    let open = |version: &str| {
+      Database::<Test>::builder()
+        .manual_save()
+        .app_version(version)
+        .check_app_version(|v| v <= "2.0")
+        .open(&path)
+    };
+    let db = open("2.0").unwrap();
+    assert_eq!(db.metadata().app_version.as_deref(), Some("1.0"));
+    db.get_mut().a = 2;
+    db.flush().unwrap();
+    assert_eq!(db.metadata().app_version.as_deref(), Some("2.0"));
+    assert_eq!(db.loaded_metadata().app_version.as_deref(), Some("1.0"));
+    db.close().unwrap();
+    let saved = read_metadata(&path).unwrap().unwrap();
+    assert_eq!(saved.app_version.as_deref(), Some("2.0"));
+
+    let db = open("3.0").unwrap();
+    db.get_mut().a = 3;
+    db.close().unwrap();
+    assert!(matches!(open("2.0"), Err(DataError::AppVersion(v)) if v == "3.0"));
+  }
+
+  #[test]
+  fn manual() {
+    let path = std::env::temp_dir().join("floppadb-manual.db");
+    let _ = std::fs::remove_file(&path);
+    let saved = Arc::new(Mutex::new(vec![]));
+    let s = saved.clone();
+    let db = Database::<Test>::builder()
+      .manual_save()
+      .on_saved(move |saved| s.lock().unwrap().push(saved.clone()))
+      .open(&path)
+      .unwrap();
+    db.get_mut().a = 1;
+    std::thread::sleep(std::time::Duration::from_millis(50));
+    assert!(!path.exists());
+    db.close().unwrap();
+    let saved = saved.lock().unwrap();
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].version, 1);
+    assert_eq!(
+      saved[0].bytes,
+      Some(std::fs::metadata(&path).unwrap().len() as usize)
+    );
+    assert_eq!(Database::<Test>::new(&path).unwrap().get().a, 1);
   }
 }
@@ -0,0 +1,23 @@
+use std::time::SystemTime;
+use serde::{Serialize, Deserialize};
+
+/// Information stored alongside the data.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Metadata {
+  pub created: SystemTime,
+  /// When the data was last saved.
+  pub modified: SystemTime,
+  /// The version of the application that last saved the data, see [`crate::Builder::app_version`].
+  pub app_version: Option<String>,
+}
+
+impl Metadata {
+  pub(crate) fn new() -> Self {
+    let now = SystemTime::now();
+    Self {
+      created: now,
+      modified: now,
+      app_version: None,
+    }
+  }
+}
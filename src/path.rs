@@ -0,0 +1,91 @@
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::Value;
+use crate::{Database, DataError};
+
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> Database<T> {
+  /// Reads the value at `path`, e.g. `settings.network.proxy`, without knowing the type of the
+  /// data. Numbers index into sequences, and the empty path is the whole data.
+  ///
+  /// The data is converted to JSON to find the value, so this is meant for tooling like a CLI or
+  /// a debug endpoint rather than regular reads.
+  pub fn get_path<V: DeserializeOwned>(&self, path: &str) -> Result<V, DataError> {
+    let value = serde_json::to_value(&*self.get())?;
+    let mut node = &value;
+    for key in keys(path) {
+      node = match node {
+        Value::Object(map) => map.get(key),
+        Value::Array(items) => key.parse().ok().and_then(|i: usize| items.get(i)),
+        _ => None,
+      }
+      .ok_or_else(|| DataError::NoPath(path.to_string()))?;
+    }
+    Ok(V::deserialize(node)?)
+  }
+
+  /// Replaces the existing value at `path` with `value`, see [`Database::get_path`].
+  ///
+  /// Fails without changing anything if the path doesn't exist or the data doesn't deserialize
+  /// with the new value. The whole data goes through JSON, so types JSON can't represent, like
+  /// maps with non-string keys or non-finite floats, make this fail or lose those values.
+  pub fn set_path<V: Serialize>(&self, path: &str, value: V) -> Result<(), DataError> {
+    let mut guard = self.write(None);
+    // only committed once the new data is built
+    guard.4 = false;
+    let mut root = serde_json::to_value(&*guard)?;
+    let mut node = &mut root;
+    for key in keys(path) {
+      node = match node {
+        Value::Object(map) => map.get_mut(key),
+        Value::Array(items) => key.parse().ok().and_then(|i: usize| items.get_mut(i)),
+        _ => None,
+      }
+      .ok_or_else(|| DataError::NoPath(path.to_string()))?;
+    }
+    *node = serde_json::to_value(value)?;
+    *guard = T::deserialize(root)?;
+    guard.4 = true;
+    Ok(())
+  }
+}
+
+fn keys(path: &str) -> impl Iterator<Item = &str> {
+  path.split('.').filter(|k| !k.is_empty())
+}
+
+#[cfg(test)]
+mod test {
+  use serde::Deserialize;
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Default)]
+  struct Network {
+    proxy: Option<String>,
+    ports: Vec<u16>,
+  }
+
+  #[derive(Serialize, Deserialize, Default)]
+  struct Settings {
+    network: Network,
+  }
+
+  #[test]
+  fn test() {
+    let db = Database::new_custom(Settings::default(), |_| {});
+    db.set_path("network.proxy", "socks5://floppa").unwrap();
+    db.set_path("network.ports", [80, 443]).unwrap();
+    assert_eq!(db.get().network.proxy.as_deref(), Some("socks5://floppa"));
+    assert_eq!(db.get_path::<u16>("network.ports.1").unwrap(), 443);
+    assert!(matches!(
+      db.get_path::<u16>("network.ports.2"),
+      Err(DataError::NoPath(_))
+    ));
+    let version = db.version();
+    assert!(db.set_path("network.ports", "floppa").is_err());
+    assert!(matches!(
+      db.set_path("network.floppa", 1),
+      Err(DataError::NoPath(_))
+    ));
+    assert_eq!(db.get().network.ports, [80, 443]);
+    assert_eq!(db.version(), version);
+  }
+}
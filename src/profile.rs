@@ -0,0 +1,143 @@
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What the saves of a database cost, see [`crate::Builder::profile_saves`].
+#[derive(Clone, Copy, Default, PartialEq, Debug)]
+pub struct SaveProfile {
+  pub saves: u64,
+  /// Bytes of the serialized data that differed from the previous save.
+  pub changed: u64,
+  /// Bytes written to the file, after compression and encryption.
+  pub written: u64,
+  pub serialize: Duration,
+  /// Time spent encoding and writing.
+  pub io: Duration,
+}
+
+impl SaveProfile {
+  /// How many bytes were written for each byte that changed.
+  pub fn amplification(&self) -> f64 {
+    self.written as f64 / self.changed.max(1) as f64
+  }
+
+  fn add(&mut self, other: &SaveProfile) {
+    self.saves += other.saves;
+    self.changed += other.changed;
+    self.written += other.written;
+    self.serialize += other.serialize;
+    self.io += other.io;
+  }
+}
+
+impl fmt::Display for SaveProfile {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{} saves, {} bytes changed, {} bytes written ({:.1}x), {:?} serializing, {:?} writing",
+      self.saves,
+      self.changed,
+      self.written,
+      self.amplification(),
+      self.serialize,
+      self.io
+    )
+  }
+}
+
+/// Records every save and logs a [`SaveProfile`] of the last interval through the `log` crate.
+pub(crate) struct Profiler {
+  every: Duration,
+  state: Mutex<State>,
+}
+
+struct State {
+  total: SaveProfile,
+  interval: SaveProfile,
+  logged: Instant,
+  /// The previous payload, to count how much of it changed.
+  last: Vec<u8>,
+  /// Time spent serializing the payload that is about to be saved.
+  serialize: Duration,
+}
+
+impl Profiler {
+  pub fn new(every: Duration) -> Self {
+    Self {
+      every,
+      state: Mutex::new(State {
+        total: SaveProfile::default(),
+        interval: SaveProfile::default(),
+        logged: Instant::now(),
+        last: vec![],
+        serialize: Duration::ZERO,
+      }),
+    }
+  }
+
+  pub fn serialized(&self, time: Duration) {
+    self.state.lock().unwrap().serialize += time;
+  }
+
+  /// Counts the bytes of `payload` that changed since the last call, keeping it for the next.
+  pub fn changed(&self, payload: &[u8]) -> u64 {
+    let mut state = self.state.lock().unwrap();
+    let common = payload.len().min(state.last.len());
+    let changed = payload[..common]
+      .iter()
+      .zip(&state.last[..common])
+      .filter(|(a, b)| a != b)
+      .count()
+      + payload.len().abs_diff(state.last.len());
+    state.last.clear();
+    state.last.extend_from_slice(payload);
+    changed as u64
+  }
+
+  pub fn saved(&self, changed: u64, written: usize, io: Duration) {
+    let mut state = self.state.lock().unwrap();
+    let save = SaveProfile {
+      saves: 1,
+      changed,
+      written: written as u64,
+      serialize: std::mem::take(&mut state.serialize),
+      io,
+    };
+    state.total.add(&save);
+    state.interval.add(&save);
+    if state.logged.elapsed() >= self.every {
+      log::info!("{}", state.interval);
+      state.interval = SaveProfile::default();
+      state.logged = Instant::now();
+    }
+  }
+
+  pub fn total(&self) -> SaveProfile {
+    self.state.lock().unwrap().total
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use crate::Database;
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-profile.db");
+    let _ = std::fs::remove_file(&path);
+    let db = Database::<Vec<u8>>::builder()
+      .manual_save()
+      .profile_saves(std::time::Duration::from_secs(3600))
+      .open(&path)
+      .unwrap();
+    db.get_mut().extend([0; 100]);
+    db.flush().unwrap();
+    db.get_mut()[0] = 1;
+    db.flush().unwrap();
+    let profile = db.save_profile().unwrap();
+    assert_eq!(profile.saves, 2);
+    assert_eq!(profile.changed, 8 + 100 + 1);
+    assert_eq!(profile.written, 2 * std::fs::metadata(&path).unwrap().len());
+    assert!(profile.amplification() > 1.0);
+  }
+}
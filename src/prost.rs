@@ -0,0 +1,56 @@
+use std::path::Path;
+use prost::Message;
+use crate::{Builder, Database, DataError};
+
+impl<T: Message + Default + Send + Sync + 'static> Builder<T> {
+  /// Opens a database at `path` that is saved as a bare Protobuf message, so services in other
+  /// languages can read it with the same schema.
+  ///
+  /// The file has no header, so the metadata isn't stored, and compression, encryption, signing
+  /// and delta saves fail with [`DataError::Unsupported`].
+  pub fn open_prost<P: AsRef<Path>>(self, path: P) -> Result<Database<T>, DataError> {
+    self.open_encoded(
+      path.as_ref(),
+      true,
+      |payload| Ok(T::decode(payload)?),
+      |data| Ok(data.encode_to_vec()),
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[derive(Clone, PartialEq, Message)]
+  struct Test {
+    #[prost(uint32, tag = "1")]
+    a: u32,
+    #[prost(string, tag = "2")]
+    name: String,
+  }
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-prost.db");
+    let _ = std::fs::remove_file(&path);
+    let db = Database::<Test>::builder()
+      .manual_save()
+      .open_prost(&path)
+      .unwrap();
+    db.get_mut().name = "floppa".to_string();
+    db.flush().unwrap();
+    let test = Test::decode(&std::fs::read(&path).unwrap()[..]).unwrap();
+    assert_eq!(test.name, "floppa");
+
+    let db = Database::<Test>::builder().open_prost(&path).unwrap();
+    assert_eq!(*db.get(), test);
+
+    assert!(matches!(
+      Database::<Test>::builder()
+        .delta_saves(10)
+        .open_prost(&path),
+      Err(DataError::Unsupported("delta_saves"))
+    ));
+  }
+}
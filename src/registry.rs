@@ -0,0 +1,125 @@
+//! Lets shutdown code reach every database opened with [`crate::Builder::register`].
+
+use std::any;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::sync::atomic::Ordering;
+use crate::{Database, DataError, Inner};
+
+/// A registered database, see [`open_databases`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct OpenDatabase {
+  /// The file it is saved to, if it is saved to a file.
+  pub path: Option<PathBuf>,
+  /// The type of its data.
+  pub type_name: &'static str,
+  /// Whether it has writes that weren't saved yet.
+  pub dirty: bool,
+  /// See [`Database::version`].
+  pub version: u64,
+}
+
+trait Registered: Send + Sync {
+  /// Whether the database is still open, without locking it.
+  fn alive(&self) -> bool;
+  /// `None` once the database has been dropped.
+  fn info(&self) -> Option<OpenDatabase>;
+  fn flush(&self) -> Option<Result<(), DataError>>;
+}
+
+struct Entry<T>(Weak<RwLock<Inner<T>>>);
+
+impl<T: Send + Sync> Registered for Entry<T> {
+  fn alive(&self) -> bool {
+    self.0.strong_count() > 0
+  }
+
+  fn info(&self) -> Option<OpenDatabase> {
+    let db = self.0.upgrade()?;
+    let inner = db.read().unwrap();
+    Some(OpenDatabase {
+      #[cfg(feature = "bincode")]
+      path: inner
+        .disk
+        .as_ref()
+        .and_then(|d| d.path())
+        .map(PathBuf::from),
+      #[cfg(not(feature = "bincode"))]
+      path: None,
+      type_name: any::type_name::<T>(),
+      dirty: inner.dirty.load(Ordering::Relaxed),
+      version: inner.version.load(Ordering::Relaxed),
+    })
+  }
+
+  fn flush(&self) -> Option<Result<(), DataError>> {
+    Some(self.0.upgrade()?.read().unwrap().save())
+  }
+}
+
+static REGISTRY: Mutex<Vec<Arc<dyn Registered>>> = Mutex::new(vec![]);
+
+pub(crate) fn register<T: Send + Sync + 'static>(db: &Database<T>) {
+  REGISTRY
+    .lock()
+    .unwrap()
+    .push(Arc::new(Entry(Arc::downgrade(&db.0))));
+}
+
+/// The registered databases, forgetting those that were dropped. The registry is only locked to
+/// copy the list, so no database is locked while holding it.
+fn registered() -> Vec<Arc<dyn Registered>> {
+  let mut registry = REGISTRY.lock().unwrap();
+  registry.retain(|r| r.alive());
+  registry.clone()
+}
+
+/// Every database opened with [`crate::Builder::register`] that is still open.
+pub fn open_databases() -> Vec<OpenDatabase> {
+  registered().iter().filter_map(|r| r.info()).collect()
+}
+
+/// Saves every registered database that changed, e.g. before the process exits.
+///
+/// All of them are flushed even if one fails, then the first error is returned.
+pub fn flush_all() -> Result<(), DataError> {
+  let mut result = Ok(());
+  for r in registered() {
+    if let Some(Err(e)) = r.flush() {
+      if result.is_ok() {
+        result = Err(e);
+      }
+    }
+  }
+  result
+}
+
+#[cfg(test)]
+mod test {
+  use crate::Builder;
+  use super::*;
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-registry.db");
+    let _ = std::fs::remove_file(&path);
+    let db = Builder::<Vec<u32>>::new()
+      .manual_save()
+      .register()
+      .open(&path)
+      .unwrap();
+    db.get_mut().push(1);
+    let find = || {
+      open_databases()
+        .into_iter()
+        .find(|d| d.path.as_ref() == Some(&path))
+    };
+    let open = find().unwrap();
+    assert!(open.dirty && open.type_name.ends_with("Vec<u32>"));
+
+    flush_all().unwrap();
+    assert!(!find().unwrap().dirty && path.exists());
+    drop(db);
+    assert_eq!(find(), None);
+  }
+}
@@ -0,0 +1,68 @@
+use std::sync::{Arc, RwLock, Weak};
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::thread;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::Database;
+
+/// A read-only copy of a database, see [`Database::replica`].
+pub struct Replica<T>(Arc<RwLock<Arc<T>>>);
+
+impl<T> Clone for Replica<T> {
+  fn clone(&self) -> Self {
+    Self(self.0.clone())
+  }
+}
+
+impl<T> Replica<T> {
+  /// The copy as of the last refresh.
+  pub fn get(&self) -> Arc<T> {
+    self.0.read().unwrap().clone()
+  }
+}
+
+impl<T: Serialize + DeserializeOwned + Clone + Send + Sync + 'static> Database<T> {
+  /// Returns a handle with its own copy of the data, refreshed every `interval` if it changed.
+  ///
+  /// Reading from a replica never waits for the database's lock, but can be up to `interval`
+  /// behind. The refresh thread stops once every clone of the replica is dropped.
+  pub fn replica(&self, interval: Duration) -> Replica<T> {
+    let (data, mut version) = {
+      let inner = self.0.read().unwrap();
+      (inner.data.clone(), inner.version.load(Ordering::Relaxed))
+    };
+    let replica = Replica(Arc::new(RwLock::new(Arc::new(data))));
+    let weak = Arc::downgrade(&replica.0);
+    let db = self.clone();
+    thread::spawn(move || loop {
+      thread::sleep(interval);
+      let Some(copy) = Weak::upgrade(&weak) else {
+        break;
+      };
+      if db.version() != version {
+        let inner = db.0.read().unwrap();
+        version = inner.version.load(Ordering::Relaxed);
+        let data = Arc::new(inner.data.clone());
+        drop(inner);
+        *copy.write().unwrap() = data;
+      }
+    });
+    replica
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let db = Database::new_custom(0u32, |_| {});
+    let replica = db.replica(Duration::from_millis(10));
+    assert_eq!(*replica.get(), 0);
+    *db.get_mut() = 1;
+    while *replica.get() != 1 {
+      thread::sleep(Duration::from_millis(1));
+    }
+  }
+}
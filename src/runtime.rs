@@ -0,0 +1,194 @@
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use serde::{Serialize, de::DeserializeOwned};
+use tokio::{task, time};
+use tokio_stream::{Stream, StreamExt};
+use tokio_stream::wrappers::BroadcastStream;
+use crate::{Database, DataError, SavePolicy, saver};
+
+/// A committed write, see [`Database::changes`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChangeEvent {
+  /// The version after the write, see [`Database::version`].
+  pub version: u64,
+  /// The label the write was made with through [`Database::get_mut_labeled`].
+  pub label: Option<String>,
+}
+
+/// Wakes the saver task after a write, and once more when the database is dropped so it stops.
+pub(crate) struct Notify(pub Arc<tokio::sync::Notify>);
+
+impl Drop for Notify {
+  fn drop(&mut self) {
+    self.0.notify_one();
+  }
+}
+
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> Database<T> {
+  /// Saves the database from a task on the current runtime, see [`crate::Builder::open_async`].
+  pub(crate) fn spawn_task(&self, options: saver::Options) {
+    let (notify, running) = {
+      let inner = self.0.read().unwrap();
+      (inner.notify.0.clone(), inner.health.start())
+    };
+    let policy = options.policy;
+    let on_panic = options.on_panic;
+    // the task stops once the database is dropped
+    let db = Arc::downgrade(&self.0);
+    let version = {
+      let db = db.clone();
+      move || {
+        Some(
+          db.upgrade()?
+            .read()
+            .unwrap()
+            .version
+            .load(Ordering::Relaxed),
+        )
+      }
+    };
+    tokio::spawn(async move {
+      let _running = running;
+      loop {
+        match policy {
+          SavePolicy::Interval(interval) => time::sleep(interval).await,
+          _ => notify.notified().await,
+        }
+        if let SavePolicy::Debounced(quiet) = policy {
+          loop {
+            let before = version();
+            time::sleep(quiet).await;
+            if version() == before {
+              break;
+            }
+          }
+        }
+        let weak = db.clone();
+        let saved = task::spawn_blocking(move || {
+          let Some(db) = weak.upgrade() else {
+            return false;
+          };
+          let inner = db.read().unwrap();
+          inner.health.tick(inner.clock.now());
+          // retried on the next write
+          if let Err(e) = inner.save() {
+            inner.health.error(&e.to_string());
+          }
+          true
+        })
+        .await;
+        match saved {
+          Ok(true) => {}
+          Ok(false) => break,
+          Err(e) => {
+            let (Ok(e), Some(db)) = (e.try_into_panic(), db.upgrade()) else {
+              break;
+            };
+            if !saver::recover(&db, e, &on_panic) {
+              break;
+            }
+            drop(db);
+            time::sleep(saver::RETRY).await;
+            notify.notify_one();
+          }
+        }
+      }
+    });
+  }
+
+  /// Waits until every write committed before this was called has been saved.
+  pub async fn saved(&self) {
+    self.saved_version(self.version()).await
+  }
+
+  async fn saved_version(&self, version: u64) {
+    let mut saved = self.0.read().unwrap().saved.subscribe();
+    let _ = saved.wait_for(|v| *v >= version).await;
+  }
+
+  /// Saves the data on a blocking thread if it changed, waiting for a save in progress first.
+  ///
+  /// Files are synced to disk before this returns, so the writes survive a crash. Writers from
+  /// [`crate::Builder::open_with`] are only flushed.
+  pub async fn flush_async(&self) -> Result<(), DataError> {
+    let db = self.clone();
+    task::spawn_blocking(move || db.flush()).await.unwrap()
+  }
+
+  /// A stream of every write committed from now on.
+  ///
+  /// A consumer that falls more than 64 events behind skips the ones it missed, compare versions
+  /// to notice.
+  pub fn changes(&self) -> impl Stream<Item = ChangeEvent> + Send + 'static {
+    let changes = self.0.read().unwrap().changes.subscribe();
+    BroadcastStream::new(changes).filter_map(Result::ok)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[tokio::test]
+  async fn test() {
+    let path = std::env::temp_dir().join("floppadb-async.db");
+    let _ = std::fs::remove_file(&path);
+    let db = Database::<Vec<u32>>::builder()
+      .open_async(&path)
+      .await
+      .unwrap();
+    let mut changes = db.changes();
+    db.get_mut_labeled("push").push(1);
+    let event = changes.next().await.unwrap();
+    assert_eq!((event.version, event.label.as_deref()), (1, Some("push")));
+    db.saved().await;
+    assert_eq!(Database::<Vec<u32>>::new(&path).unwrap().get()[..], [1]);
+    db.get_mut().push(2);
+    db.flush_async().await.unwrap();
+    assert_eq!(Database::<Vec<u32>>::new(&path).unwrap().get()[..], [1, 2]);
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn failed_save() {
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::time::Duration;
+    use crate::Metadata;
+
+    // the first save is slow and fails
+    let saves = std::sync::Arc::new(AtomicU32::new(0));
+    let s = saves.clone();
+    let save = Box::new(move |_: &u32, _: &Metadata| {
+      if s.fetch_add(1, Ordering::Relaxed) == 0 {
+        std::thread::sleep(Duration::from_millis(50));
+        return Err(DataError::NoFile);
+      }
+      Ok(None)
+    });
+    let db = Database::create(0u32, Metadata::new(), save, Database::builder()).unwrap();
+    db.spawn_task(saver::Options::default());
+    *db.get_mut() = 1;
+    time::sleep(Duration::from_millis(10)).await;
+    // waits for the save in progress and retries it instead of waiting for it forever
+    time::timeout(Duration::from_secs(5), db.flush_async())
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(saves.load(Ordering::Relaxed), 2);
+
+    // a panicking save is handled like on the saver thread
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut builder =
+      Database::builder().on_saver_panic(saver::SaverPanic::Report(Box::new(move |e| {
+        tx.send(e.to_string()).unwrap()
+      })));
+    let options = std::mem::take(&mut builder.saver);
+    let save =
+      Box::new(|_: &u32, _: &Metadata| -> Result<Option<usize>, DataError> { panic!("disk full") });
+    let db = Database::create(0u32, Metadata::new(), save, builder).unwrap();
+    db.spawn_task(options);
+    *db.get_mut() = 1;
+    let e = time::timeout(Duration::from_secs(5), rx.recv()).await;
+    assert_eq!(e.unwrap().as_deref(), Some("disk full"));
+    assert_eq!(db.saver_health().last_error.as_deref(), Some("disk full"));
+  }
+}
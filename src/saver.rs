@@ -0,0 +1,330 @@
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::time::{Scheduler, ThreadScheduler, Wait};
+use crate::{DataError, Inner};
+
+/// What the saver thread does when saving panics, see [`crate::Builder::on_saver_panic`].
+///
+/// Without one the thread just dies, and the database is no longer saved.
+pub enum SaverPanic {
+  /// Carry on and retry the save.
+  Restart,
+  /// Call the function with the panic message, then stop saving.
+  Report(Box<dyn Fn(&str) + Send>),
+  /// Poison the database so that every later access panics, then stop saving.
+  Poison,
+}
+
+/// When the saver saves after a write, see [`crate::Builder::save_policy`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SavePolicy {
+  /// Save as soon as possible after every write.
+  #[default]
+  Immediate,
+  /// Save once there were no writes for the duration, so a burst of writes is saved once.
+  Debounced(Duration),
+  /// Check for writes to save every interval.
+  Interval(Duration),
+  /// Don't save in the background at all, only with [`crate::Database::flush`].
+  Manual,
+}
+
+/// A successful save, see [`crate::Builder::on_saved`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Saved {
+  /// The version that was saved, see [`crate::Database::version`].
+  pub version: u64,
+  /// How many bytes were written, `None` for databases saved by a custom function.
+  pub bytes: Option<usize>,
+  /// How long it took to serialize and write.
+  pub duration: Duration,
+}
+
+pub(crate) type OnSaved = Box<dyn Fn(&Saved) + Send + Sync>;
+
+/// What a database does on writes once its saver has stopped, see
+/// [`crate::Builder::on_dead_saver`].
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DeadSaver {
+  /// Log a warning through the `log` crate on the first write.
+  Warn,
+  /// Panic on every write.
+  Panic,
+}
+
+/// What the saver is doing, see [`crate::Database::saver_health`].
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SaverStatus {
+  /// Whether the saver thread or task is running, always false with
+  /// [`SavePolicy::Manual`].
+  pub running: bool,
+  /// When the saver last checked whether there was anything to save.
+  pub last_tick: Option<SystemTime>,
+  /// The message of the last save that failed or panicked.
+  pub last_error: Option<String>,
+}
+
+const RUNNING: u8 = 1;
+const DEAD: u8 = 2;
+
+/// Tracks the saver for [`SaverStatus`].
+#[derive(Default)]
+pub(crate) struct Health {
+  /// 0 until the saver is started, then [`RUNNING`] and finally [`DEAD`].
+  state: AtomicU8,
+  /// Milliseconds since the unix epoch, 0 if it never ticked.
+  tick: AtomicU64,
+  error: Mutex<Option<String>>,
+  warned: AtomicBool,
+}
+
+impl Health {
+  /// Marks the saver as running until the returned guard is dropped, including by a panic.
+  pub fn start(self: &Arc<Self>) -> Running {
+    self.state.store(RUNNING, Ordering::Relaxed);
+    Running(self.clone())
+  }
+
+  pub fn tick(&self, now: SystemTime) {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+    self.tick.store(now.as_millis() as u64, Ordering::Relaxed);
+  }
+
+  pub fn error(&self, e: &str) {
+    *self.error.lock().unwrap() = Some(e.to_string());
+  }
+
+  pub fn status(&self) -> SaverStatus {
+    let tick = self.tick.load(Ordering::Relaxed);
+    SaverStatus {
+      running: self.state.load(Ordering::Relaxed) == RUNNING,
+      last_tick: (tick != 0).then(|| UNIX_EPOCH + Duration::from_millis(tick)),
+      last_error: self.error.lock().unwrap().clone(),
+    }
+  }
+
+  /// Warns about a dead saver, or returns true if the write should panic instead.
+  pub fn check(&self, on_dead: Option<DeadSaver>) -> bool {
+    if on_dead.is_none() || self.state.load(Ordering::Relaxed) != DEAD {
+      return false;
+    }
+    if on_dead == Some(DeadSaver::Warn) && !self.warned.swap(true, Ordering::Relaxed) {
+      log::warn!("the saver has stopped, writes are no longer saved");
+    }
+    on_dead == Some(DeadSaver::Panic)
+  }
+}
+
+pub(crate) struct Running(Arc<Health>);
+
+impl Drop for Running {
+  fn drop(&mut self) {
+    self.0.state.store(DEAD, Ordering::Relaxed);
+  }
+}
+
+/// How the saver thread is set up, see [`crate::Builder`].
+#[derive(Default)]
+pub(crate) struct Options {
+  pub name: Option<String>,
+  #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+  pub priority: Option<i32>,
+  pub on_panic: Option<SaverPanic>,
+  pub policy: SavePolicy,
+  /// `None` for a [`ThreadScheduler`].
+  pub scheduler: Option<Arc<dyn Scheduler>>,
+}
+
+/// Starts the task that saves `db` whenever it is dirty.
+pub(crate) fn spawn<T: Send + Sync + 'static>(
+  db: Arc<RwLock<Inner<T>>>,
+  options: Options,
+) -> Result<(), DataError> {
+  // the niceness is set on the thread running the saver, which a scheduler may share
+  if options.priority.is_some() && options.scheduler.is_some() {
+    return Err(DataError::Unsupported("saver_priority"));
+  }
+  let (running, clock, writes) = {
+    let inner = db.read().unwrap();
+    (
+      inner.health.start(),
+      inner.clock.clone(),
+      inner.writes.writes(),
+    )
+  };
+  #[cfg(target_os = "linux")]
+  let mut priority = options.priority;
+  let policy = options.policy;
+  let on_panic = options.on_panic;
+  // the version the debounce is waiting to settle
+  let mut settling = None;
+  // the saver stops once the database is dropped
+  let db = Arc::downgrade(&db);
+  let step = move || {
+    let _running = &running;
+    #[cfg(target_os = "linux")]
+    if let Some(priority) = priority.take() {
+      unsafe { libc::setpriority(libc::PRIO_PROCESS, libc::gettid() as _, priority) };
+    }
+    let db = db.upgrade()?;
+    let inner = db.read().unwrap();
+    inner.health.tick(inner.clock.now());
+    let dirty = inner.dirty.load(Ordering::Relaxed);
+    match policy {
+      SavePolicy::Debounced(quiet) if dirty => {
+        let version = inner.version.load(Ordering::Relaxed);
+        if settling.replace(version) != Some(version) {
+          return Some(Wait::Sleep(quiet));
+        }
+        settling = None;
+      }
+      SavePolicy::Interval(_) => {}
+      _ if !dirty => return Some(Wait::Write),
+      _ => {}
+    }
+    let wait = match policy {
+      SavePolicy::Interval(interval) => Wait::Sleep(interval),
+      _ => Wait::Write,
+    };
+    let Err(e) = panic::catch_unwind(AssertUnwindSafe(|| inner.save().unwrap())) else {
+      return Some(wait);
+    };
+    drop(inner);
+    recover(&db, e, &on_panic).then_some(Wait::Sleep(RETRY))
+  };
+  let scheduler = options
+    .scheduler
+    .unwrap_or_else(|| Arc::new(ThreadScheduler));
+  scheduler.spawn(options.name, clock, writes, Box::new(step))?;
+  Ok(())
+}
+
+/// Handles a save that panicked with `e` following `on_panic`, returning whether to retry it.
+pub(crate) fn recover<T>(
+  db: &RwLock<Inner<T>>,
+  e: Box<dyn Any + Send>,
+  on_panic: &Option<SaverPanic>,
+) -> bool {
+  {
+    let inner = db.read().unwrap();
+    inner.health.error(message(&*e));
+    if let Some(SaverPanic::Restart) = on_panic {
+      inner.dirty.store(true, Ordering::Relaxed);
+      return true;
+    }
+  }
+  match on_panic {
+    Some(SaverPanic::Report(f)) => f(message(&*e)),
+    Some(SaverPanic::Poison) => {
+      // unwinding while holding the write lock poisons it
+      let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+        let _guard = db.write();
+        panic::resume_unwind(e);
+      }));
+    }
+    _ => panic::resume_unwind(e),
+  }
+  false
+}
+
+/// How long [`SaverPanic::Restart`] waits before retrying.
+pub(crate) const RETRY: Duration = Duration::from_millis(100);
+
+fn message(e: &(dyn Any + Send)) -> &str {
+  match e.downcast_ref::<&str>() {
+    Some(s) => s,
+    None => e.downcast_ref::<String>().map_or("save panicked", |s| s),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::mpsc;
+  use std::thread;
+  use std::time::Duration;
+  use crate::Database;
+  use super::*;
+
+  #[test]
+  fn test() {
+    let (tx, rx) = mpsc::channel();
+    let db = Database::builder()
+      .saver_name("floppadb-saver")
+      .on_saver_panic(SaverPanic::Report(Box::new(move |e| {
+        tx.send((thread::current().name().map(str::to_string), e.to_string()))
+          .unwrap()
+      })))
+      .build(0u32, |_| panic!("disk full"))
+      .unwrap();
+    *db.get_mut() = 1;
+    assert_eq!(
+      rx.recv_timeout(Duration::from_secs(1)),
+      Ok((Some("floppadb-saver".to_string()), "disk full".to_string()))
+    );
+
+    let db = Database::builder()
+      .on_saver_panic(SaverPanic::Poison)
+      .build(0u32, |_| panic!("disk full"))
+      .unwrap();
+    *db.get_mut() = 1;
+    while !db.0.is_poisoned() {
+      thread::yield_now();
+    }
+
+    let db = Database::builder()
+      .on_dead_saver(DeadSaver::Panic)
+      .build(0u32, |_| panic!("disk full"))
+      .unwrap();
+    assert!(db.saver_health().running);
+    *db.get_mut() = 1;
+    while db.saver_health().running {
+      thread::yield_now();
+    }
+    assert_eq!(db.saver_health().last_error.as_deref(), Some("disk full"));
+    assert!(panic::catch_unwind(|| *db.get_mut() = 2).is_err());
+    assert!(!db.0.is_poisoned());
+  }
+
+  #[test]
+  fn debounced() {
+    let saves = Arc::new(AtomicU64::new(0));
+    let s = saves.clone();
+    let db = Database::builder()
+      .save_policy(SavePolicy::Debounced(Duration::from_millis(50)))
+      .build(0u32, move |_| {
+        s.fetch_add(1, Ordering::Relaxed);
+      })
+      .unwrap();
+    for i in 0..5 {
+      *db.get_mut() = i;
+      thread::sleep(Duration::from_millis(5));
+    }
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(saves.load(Ordering::Relaxed), 1);
+
+    let db = Database::builder()
+      .saver_priority(10)
+      .scheduler(ThreadScheduler)
+      .build(0u32, |_| {});
+    assert!(matches!(db, Err(DataError::Unsupported("saver_priority"))));
+  }
+
+  #[test]
+  fn dropped() {
+    let db = Database::builder().build(0u32, |_| {}).unwrap();
+    *db.get_mut() = 1;
+    let inner = Arc::downgrade(&db.0);
+    drop(db);
+    let deadline = std::time::Instant::now() + Duration::from_secs(5);
+    while inner.strong_count() > 0 {
+      assert!(
+        std::time::Instant::now() < deadline,
+        "the saver kept the database"
+      );
+      thread::sleep(Duration::from_millis(1));
+    }
+  }
+}
@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use serde::{Serialize, de::DeserializeOwned};
+#[cfg(feature = "zstd")]
+use crate::Dictionary;
+use crate::{Builder, Database, DataError};
+
+/// Many databases of the same type, one file per key in a directory, e.g. per user state.
+///
+/// Databases are opened when first accessed, and only `capacity` of them are kept open, closing
+/// the least recently used. They have no saver thread, so writes are saved when a database is
+/// closed or by [`DatabaseSet::flush_all`] instead.
+pub struct DatabaseSet<T> {
+  dir: PathBuf,
+  capacity: usize,
+  builder: Box<dyn Fn() -> Builder<T> + Send + Sync>,
+  open: Mutex<Open<T>>,
+}
+
+struct Open<T> {
+  /// Each with the tick it was last used at.
+  dbs: HashMap<String, (Database<T>, u64)>,
+  tick: u64,
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Send + Sync + 'static> DatabaseSet<T> {
+  /// Keeps the databases in `dir`, creating it when the first one is saved.
+  pub fn new<P: AsRef<Path>>(dir: P, capacity: usize) -> Self {
+    Self {
+      dir: dir.as_ref().to_path_buf(),
+      capacity: capacity.max(1),
+      builder: Box::new(Builder::new),
+      open: Mutex::new(Open {
+        dbs: HashMap::new(),
+        tick: 0,
+      }),
+    }
+  }
+
+  /// Opens every database with a builder from `f`, always with [`Builder::manual_save`].
+  pub fn builder<F: Fn() -> Builder<T> + Send + Sync + 'static>(mut self, f: F) -> Self {
+    self.builder = Box::new(f);
+    self
+  }
+
+  /// The database for `key`, opening it if it isn't open.
+  ///
+  /// Keys are used as file names, so they can't be empty, start with a dot or contain slashes.
+  pub fn get(&self, key: &str) -> Result<Database<T>, DataError> {
+    if key.is_empty() || key.starts_with('.') || key.contains(['/', '\\']) {
+      let message = format!("invalid database key {:?}", key);
+      return Err(io::Error::new(ErrorKind::InvalidInput, message).into());
+    }
+    let mut open = self.open.lock().unwrap();
+    open.tick += 1;
+    let tick = open.tick;
+    if let Some((db, used)) = open.dbs.get_mut(key) {
+      *used = tick;
+      return Ok(db.clone());
+    }
+    open.evict(self.capacity - 1)?;
+    fs::create_dir_all(&self.dir)?;
+    let db = (self.builder)()
+      .manual_save()
+      .open(self.dir.join(format!("{}.db", key)))?;
+    open.dbs.insert(key.to_string(), (db.clone(), tick));
+    Ok(db)
+  }
+
+  /// The keys of every database in the directory, open or not.
+  pub fn keys(&self) -> Result<Vec<String>, DataError> {
+    let mut keys = vec![];
+    match fs::read_dir(&self.dir) {
+      Ok(entries) => {
+        for entry in entries {
+          let name = entry?.file_name();
+          let key = name.to_str().and_then(|n| n.strip_suffix(".db"));
+          if let Some(key) = key.filter(|k| !k.is_empty() && !k.starts_with('.')) {
+            keys.push(key.to_string());
+          }
+        }
+      }
+      Err(e) if e.kind() == ErrorKind::NotFound => {}
+      Err(e) => return Err(e.into()),
+    }
+    for key in self.open.lock().unwrap().dbs.keys() {
+      if !keys.contains(key) {
+        keys.push(key.clone());
+      }
+    }
+    keys.sort();
+    Ok(keys)
+  }
+
+  /// Trains a zstd dictionary of up to `max_size` bytes on the saved databases, see
+  /// [`Dictionary::train`].
+  #[cfg(feature = "zstd")]
+  pub fn train_dictionary(&self, max_size: usize) -> Result<Dictionary, DataError> {
+    let paths = self.keys()?.into_iter();
+    Dictionary::train(
+      paths.map(|key| self.dir.join(format!("{}.db", key))),
+      max_size,
+    )
+  }
+
+  pub fn is_open(&self, key: &str) -> bool {
+    self.open.lock().unwrap().dbs.contains_key(key)
+  }
+
+  /// Saves every open database that changed.
+  pub fn flush_all(&self) -> Result<(), DataError> {
+    for (db, _) in self.open.lock().unwrap().dbs.values() {
+      db.flush()?;
+    }
+    Ok(())
+  }
+
+  /// Saves and closes every open database that isn't used elsewhere.
+  pub fn close_all(&self) -> Result<(), DataError> {
+    self.open.lock().unwrap().evict(0)
+  }
+}
+
+impl<T> Open<T> {
+  /// Closes the least recently used databases until at most `keep` are open.
+  ///
+  /// Databases with handles outside the set stay open, since closing one would let a later
+  /// [`DatabaseSet::get`] load it again while it is still being written to.
+  fn evict(&mut self, keep: usize) -> Result<(), DataError>
+  where
+    T: Send + Sync + 'static,
+  {
+    let mut idle: Vec<_> = self
+      .dbs
+      .iter()
+      .filter(|(_, (db, _))| Arc::strong_count(&db.0) == 1)
+      .map(|(key, (_, used))| (*used, key.clone()))
+      .collect();
+    idle.sort();
+    for (_, key) in idle.into_iter().take(self.dbs.len().saturating_sub(keep)) {
+      self.dbs[&key].0.flush()?;
+      self.dbs.remove(&key);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let dir = std::env::temp_dir().join("floppadb-set");
+    let _ = fs::remove_dir_all(&dir);
+    let set = DatabaseSet::<Vec<u32>>::new(&dir, 2);
+    set.get("a").unwrap().get_mut().push(1);
+    let b = set.get("b").unwrap();
+    b.get_mut().push(2);
+    set.get("a").unwrap();
+    set.get("c").unwrap().get_mut().push(3);
+    assert!(!set.is_open("a") && set.is_open("b"));
+    drop(b);
+    set.get("d").unwrap();
+    assert!(!set.is_open("b") && set.is_open("c") && set.is_open("d"));
+    assert_eq!(*set.get("b").unwrap().get(), [2]);
+    assert!(set.get("../e").is_err());
+
+    set.close_all().unwrap();
+    assert_eq!(set.keys().unwrap(), ["a", "b", "c"]);
+    assert_eq!(
+      *DatabaseSet::<Vec<u32>>::new(&dir, 1)
+        .get("c")
+        .unwrap()
+        .get(),
+      [3]
+    );
+  }
+}
@@ -0,0 +1,128 @@
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::disk::Disk;
+use crate::format::Format;
+use crate::{Builder, Database, DataError, Metadata};
+
+/// Several independently typed databases saved to one file, see [`Store::section`].
+///
+/// Each section is a [`Database`] with its own dirty flag and saver. Saving a section only
+/// serializes that section, the others are written from the bytes they were last saved as.
+pub struct Store {
+  file: Arc<File>,
+  /// The sections opened so far, each a `Database<T>`.
+  opened: Mutex<HashMap<String, Box<dyn Any + Send + Sync>>>,
+}
+
+struct File {
+  disk: Disk,
+  metadata: Metadata,
+  /// Every section as it was last saved, serialized with bincode.
+  sections: Mutex<BTreeMap<String, Vec<u8>>>,
+}
+
+impl Store {
+  /// Opens the store at `path`, which is created on the first save.
+  pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, DataError> {
+    let (disk, sections, metadata) =
+      Disk::open(path.as_ref().to_path_buf(), Format::default(), None, false)?;
+    Ok(Self {
+      file: Arc::new(File {
+        disk,
+        metadata: metadata.unwrap_or_else(Metadata::new),
+        sections: Mutex::new(sections),
+      }),
+      opened: Mutex::default(),
+    })
+  }
+
+  /// Opens the section called `name`, starting from `T::default()` if it isn't in the file yet.
+  ///
+  /// Opening a section again returns the same database. Panics if it was opened as another type.
+  pub fn section<T>(&self, name: &str) -> Result<Database<T>, DataError>
+  where
+    T: Serialize + DeserializeOwned + Default + Send + Sync + 'static,
+  {
+    self.section_with(name, Database::builder())
+  }
+
+  /// Like [`Store::section`], but the database is configured with `builder`, e.g. to change its
+  /// [`crate::SavePolicy`].
+  ///
+  /// Options for the file itself like compression don't apply, as sections share the file.
+  pub fn section_with<T>(&self, name: &str, builder: Builder<T>) -> Result<Database<T>, DataError>
+  where
+    T: Serialize + DeserializeOwned + Default + Send + Sync + 'static,
+  {
+    let mut opened = self.opened.lock().unwrap();
+    if let Some(db) = opened.get(name) {
+      return Ok(
+        db.downcast_ref::<Database<T>>()
+          .unwrap_or_else(|| panic!("section {name:?} was opened as another type"))
+          .clone(),
+      );
+    }
+    let data = match self.file.sections.lock().unwrap().get(name) {
+      Some(bytes) => bincode::deserialize(bytes)?,
+      None => T::default(),
+    };
+    let file = self.file.clone();
+    let section = name.to_string();
+    let db = Database::spawn(
+      data,
+      self.file.metadata.clone(),
+      move |data, metadata| {
+        let payload = bincode::serialize(data)?;
+        let mut sections = file.sections.lock().unwrap();
+        sections.insert(section.clone(), payload);
+        file.disk.save(&*sections, metadata).map(Some)
+      },
+      builder,
+    )?;
+    opened.insert(name.to_string(), Box::new(db.clone()));
+    Ok(db)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use serde::Deserialize;
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Default)]
+  struct Settings {
+    volume: u8,
+  }
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-store.db");
+    let _ = std::fs::remove_file(&path);
+    let store = Store::open(&path).unwrap();
+    let settings = store.section::<Settings>("settings").unwrap();
+    let cache = store
+      .section_with("cache", Database::<Vec<String>>::builder().manual_save())
+      .unwrap();
+    settings.get_mut().volume = 7;
+    cache.get_mut().push("floppa".to_string());
+    settings.flush().unwrap();
+    assert_eq!(
+      store.section::<Settings>("settings").unwrap().get().volume,
+      7
+    );
+
+    let store = Store::open(&path).unwrap();
+    assert_eq!(
+      store.section::<Settings>("settings").unwrap().get().volume,
+      7
+    );
+    assert!(store
+      .section::<Vec<String>>("cache")
+      .unwrap()
+      .get()
+      .is_empty());
+  }
+}
@@ -0,0 +1,256 @@
+//! Helpers for checking that a database survives failed writes.
+
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use serde::{Serialize, de::DeserializeOwned};
+use crate::time::{Clock, ManualClock, Scheduler, Task, Wait, Writes};
+use crate::{Builder, Database, DataError, SavePolicy};
+pub use crate::disk::Fault;
+
+/// Injects a [`Fault`] into the next write of a database, see [`Builder::faults`].
+///
+/// Only writes to files are affected, not writers from [`Builder::open_with`].
+#[derive(Clone, Default)]
+pub struct FaultInjector(Arc<Mutex<Option<Fault>>>);
+
+impl FaultInjector {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Makes the next write fail with `fault`.
+  pub fn inject(&self, fault: Fault) {
+    *self.0.lock().unwrap() = Some(fault);
+  }
+
+  pub(crate) fn take(&self) -> Option<Fault> {
+    self.0.lock().unwrap().take()
+  }
+}
+
+/// Checks that the database at `path` holds either `old` or `new` after saving `new` over `old`
+/// fails at each point a write can fail, panicking otherwise.
+///
+/// `builder` should configure the database the same way the application does, e.g. with
+/// compression or delta saves. Runs with [`crate::SavePolicy::Manual`], and removes the file
+/// before each step.
+pub fn assert_crash_safe<T, B>(path: &Path, builder: B, old: &T, new: &T)
+where
+  T: Serialize + DeserializeOwned + Default + Clone + PartialEq + Debug + Send + Sync + 'static,
+  B: Fn() -> Builder<T>,
+{
+  let len = builder().to_bytes(new).unwrap().len();
+  let faults = [0, 1, len / 2, len - 1]
+    .map(Fault::Write)
+    .into_iter()
+    .chain([Fault::Sync, Fault::Rename]);
+  for fault in faults {
+    remove(path);
+    let injector = FaultInjector::new();
+    let db = builder()
+      .manual_save()
+      .faults(injector.clone())
+      .open(path)
+      .unwrap();
+    *db.get_mut() = old.clone();
+    db.flush().unwrap();
+    injector.inject(fault);
+    *db.get_mut() = new.clone();
+    assert!(
+      matches!(db.flush(), Err(DataError::Io(_))),
+      "{:?} wasn't injected",
+      fault
+    );
+    drop(db);
+
+    let reopened = builder().manual_save().open(path).unwrap();
+    let data = reopened.get();
+    assert!(
+      *data == *old || *data == *new,
+      "after {:?} the database holds {:?}",
+      fault,
+      *data
+    );
+  }
+  remove(path);
+}
+
+/// A [`Database`] that records its saves instead of writing them, with a virtual clock.
+///
+/// It derefs to the database, so it can be passed to code that uses one. The database's saver
+/// runs on a [`ManualClock`] and only when [`MockDatabase::advance`] moves it, so it saves
+/// according to the [`SavePolicy`] exactly like a saver thread would in that much time.
+pub struct MockDatabase<T> {
+  db: Database<T>,
+  saves: Arc<Mutex<Vec<Vec<u8>>>>,
+  clock: Arc<ManualClock>,
+  saver: Arc<Stepper>,
+}
+
+/// Keeps the saver's task so [`MockDatabase::advance`] can run it.
+#[derive(Default)]
+struct Stepper(Mutex<Option<Stepped>>);
+
+struct Stepped {
+  task: Task,
+  writes: Writes,
+  next: Next,
+}
+
+/// When the saver's task runs next.
+enum Next {
+  Now,
+  At(SystemTime),
+  Write,
+  Done,
+}
+
+impl Scheduler for Stepper {
+  fn spawn(
+    &self,
+    _: Option<String>,
+    _: Arc<dyn Clock>,
+    writes: Writes,
+    task: Task,
+  ) -> io::Result<()> {
+    *self.0.lock().unwrap() = Some(Stepped {
+      task,
+      writes,
+      next: Next::Now,
+    });
+    Ok(())
+  }
+}
+
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> MockDatabase<T> {
+  pub fn new(data: T) -> Self {
+    Self::with_policy(data, SavePolicy::Immediate)
+  }
+
+  pub fn with_policy(data: T, policy: SavePolicy) -> Self {
+    let saves = Arc::new(Mutex::new(vec![]));
+    let clock = Arc::new(ManualClock::default());
+    let saver = Arc::new(Stepper::default());
+    let s = saves.clone();
+    let db = Database::builder()
+      .save_policy(policy)
+      .clock(clock.clone())
+      .scheduler(saver.clone())
+      .build(data, move |data| {
+        s.lock().unwrap().push(bincode::serialize(data).unwrap())
+      })
+      .unwrap();
+    Self {
+      db,
+      saves,
+      clock,
+      saver,
+    }
+  }
+
+  /// Moves the virtual clock forward by `by`, running the saver whenever it would have by then.
+  ///
+  /// Like a saver thread, the saver only notices writes made since the last call when it runs,
+  /// e.g. a debounce starts once it wakes up.
+  pub fn advance(&self, by: Duration) {
+    let end = self.clock.now() + by;
+    let mut saver = self.saver.0.lock().unwrap();
+    loop {
+      let now = self.clock.now();
+      if let Some(saver) = saver.as_mut() {
+        let due = match saver.next {
+          Next::Now => true,
+          Next::At(at) => at <= now,
+          Next::Write => saver.writes.take(),
+          Next::Done => false,
+        };
+        if due {
+          saver.next = match (saver.task)() {
+            Some(Wait::Sleep(duration)) => Next::At(now + duration),
+            Some(Wait::Write) => Next::Write,
+            None => Next::Done,
+          };
+          continue;
+        }
+      }
+      let until = match saver.as_ref().map(|s| &s.next) {
+        Some(Next::At(at)) if *at < end => *at,
+        _ => end,
+      };
+      if until <= now {
+        return;
+      }
+      self.clock.advance(until.duration_since(now).unwrap());
+    }
+  }
+
+  /// The serialized payload of every save so far.
+  pub fn payloads(&self) -> Vec<Vec<u8>> {
+    self.saves.lock().unwrap().clone()
+  }
+
+  /// The data of every save so far.
+  pub fn saves(&self) -> Vec<T> {
+    let saves = self.saves.lock().unwrap();
+    saves
+      .iter()
+      .map(|p| bincode::deserialize(p).unwrap())
+      .collect()
+  }
+}
+
+impl<T> Deref for MockDatabase<T> {
+  type Target = Database<T>;
+
+  fn deref(&self) -> &Database<T> {
+    &self.db
+  }
+}
+
+fn remove(path: &Path) {
+  for suffix in ["", ".tmp", ".delta", ".delta.tmp"] {
+    let mut p = path.to_path_buf().into_os_string();
+    p.push(suffix);
+    let _ = fs::remove_file(p);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-testing.db");
+    let old = vec![1u8; 10_000];
+    let mut new = old.clone();
+    new[5_000] = 2;
+    assert_crash_safe(&path, Database::builder, &old, &new);
+    assert_crash_safe(&path, || Database::builder().delta_saves(10), &old, &new);
+  }
+
+  #[test]
+  fn mock() {
+    let db = MockDatabase::with_policy(0u32, SavePolicy::Debounced(Duration::from_secs(1)));
+    *db.get_mut() = 1;
+    db.advance(Duration::from_millis(500));
+    *db.get_mut() = 2;
+    db.advance(Duration::from_millis(500));
+    // the saver sees the second write when it wakes up, and waits for it to settle
+    db.advance(Duration::from_millis(500));
+    assert!(db.saves().is_empty());
+    db.advance(Duration::from_millis(500));
+    assert_eq!(db.saves(), [2]);
+    assert_eq!(db.metadata().modified, db.clock.now());
+    *db.get_mut() = 3;
+    db.advance(Duration::ZERO);
+    assert_eq!(db.saves(), [2]);
+    db.advance(Duration::from_secs(1));
+    assert_eq!(db.saves(), [2, 3]);
+  }
+}
@@ -0,0 +1,262 @@
+//! Abstracts time and the saver thread, so saves can be driven by something else than real
+//! threads and the system clock, see [`crate::Builder::clock`] and [`crate::Builder::scheduler`].
+//!
+//! Saving with [`crate::Builder::open_async`] always uses tokio's timers.
+
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+/// A source of time for the saver, save timestamps and [`crate::Database::on_expire`].
+pub trait Clock: Send + Sync + 'static {
+  fn now(&self) -> SystemTime;
+
+  /// Blocks the current thread until `duration` has passed on this clock.
+  fn sleep(&self, duration: Duration);
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+  fn now(&self) -> SystemTime {
+    (**self).now()
+  }
+
+  fn sleep(&self, duration: Duration) {
+    (**self).sleep(duration)
+  }
+}
+
+/// The real time, used by default.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+  fn now(&self) -> SystemTime {
+    SystemTime::now()
+  }
+
+  fn sleep(&self, duration: Duration) {
+    thread::sleep(duration)
+  }
+}
+
+/// A clock that only moves when [`ManualClock::advance`] is called, for deterministic tests.
+pub struct ManualClock {
+  now: Mutex<SystemTime>,
+  advanced: Condvar,
+}
+
+impl ManualClock {
+  pub fn new(now: SystemTime) -> Self {
+    Self {
+      now: Mutex::new(now),
+      advanced: Condvar::new(),
+    }
+  }
+
+  /// Moves the clock forward, waking the threads sleeping until then.
+  pub fn advance(&self, duration: Duration) {
+    *self.now.lock().unwrap() += duration;
+    self.advanced.notify_all();
+  }
+}
+
+impl Default for ManualClock {
+  fn default() -> Self {
+    Self::new(SystemTime::now())
+  }
+}
+
+impl Clock for ManualClock {
+  fn now(&self) -> SystemTime {
+    *self.now.lock().unwrap()
+  }
+
+  fn sleep(&self, duration: Duration) {
+    let now = self.now.lock().unwrap();
+    let until = *now + duration;
+    let _now = self.advanced.wait_while(now, |now| *now < until).unwrap();
+  }
+}
+
+/// What a [`Task`] waits for before its next step.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Wait {
+  /// Sleep on the [`Clock`].
+  Sleep(Duration),
+  /// Wait for the next write, see [`Writes`].
+  Write,
+}
+
+/// A step of a background task, returning what to wait for before the next step, or `None`
+/// once the task is done. The saver is done once its database has been dropped.
+pub type Task = Box<dyn FnMut() -> Option<Wait> + Send>;
+
+#[derive(Default)]
+struct Signal {
+  state: Mutex<SignalState>,
+  changed: Condvar,
+}
+
+#[derive(Default)]
+struct SignalState {
+  written: bool,
+  dropped: bool,
+}
+
+/// Tells a scheduler about writes to a database, for tasks waiting with [`Wait::Write`].
+pub struct Writes(Arc<Signal>);
+
+impl Writes {
+  /// Blocks until there was a write since the last call, or the database was dropped.
+  pub fn wait(&self) {
+    let state = self.0.state.lock().unwrap();
+    let mut state = self
+      .0
+      .changed
+      .wait_while(state, |s| !s.written && !s.dropped)
+      .unwrap();
+    state.written = false;
+  }
+
+  /// Like [`Writes::wait`], but returns false instead of blocking.
+  pub fn take(&self) -> bool {
+    let mut state = self.0.state.lock().unwrap();
+    std::mem::take(&mut state.written) || state.dropped
+  }
+}
+
+/// Kept by the database to signal [`Writes`], marking it dropped along with the database.
+#[derive(Default)]
+pub(crate) struct WriteSignal(Arc<Signal>);
+
+impl WriteSignal {
+  pub fn notify(&self) {
+    self.0.state.lock().unwrap().written = true;
+    self.0.changed.notify_all();
+  }
+
+  pub fn writes(&self) -> Writes {
+    Writes(self.0.clone())
+  }
+}
+
+impl Drop for WriteSignal {
+  fn drop(&mut self) {
+    self.0.state.lock().unwrap().dropped = true;
+    self.0.changed.notify_all();
+  }
+}
+
+/// Runs the saver, see [`crate::Builder::scheduler`].
+///
+/// A step may panic when saving panics and there is no [`crate::SaverPanic`] handler, after which
+/// it must not be run again.
+pub trait Scheduler: Send + Sync + 'static {
+  /// Runs `task` until it is done, waiting between the steps on `clock` or `writes`. `name` is
+  /// the name given with [`crate::Builder::saver_name`].
+  fn spawn(
+    &self,
+    name: Option<String>,
+    clock: Arc<dyn Clock>,
+    writes: Writes,
+    task: Task,
+  ) -> io::Result<()>;
+}
+
+impl<S: Scheduler + ?Sized> Scheduler for Arc<S> {
+  fn spawn(
+    &self,
+    name: Option<String>,
+    clock: Arc<dyn Clock>,
+    writes: Writes,
+    task: Task,
+  ) -> io::Result<()> {
+    (**self).spawn(name, clock, writes, task)
+  }
+}
+
+/// Runs each task on its own thread, used by default.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ThreadScheduler;
+
+impl Scheduler for ThreadScheduler {
+  fn spawn(
+    &self,
+    name: Option<String>,
+    clock: Arc<dyn Clock>,
+    writes: Writes,
+    mut task: Task,
+  ) -> io::Result<()> {
+    let mut builder = thread::Builder::new();
+    if let Some(name) = name {
+      builder = builder.name(name);
+    }
+    builder.spawn(move || {
+      while let Some(wait) = task() {
+        match wait {
+          Wait::Sleep(duration) => clock.sleep(duration),
+          Wait::Write => writes.wait(),
+        }
+      }
+    })?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::atomic::{AtomicU64, Ordering};
+  use crate::{Database, SavePolicy};
+  use super::*;
+
+  /// Keeps the task so the test can step it.
+  #[derive(Default)]
+  struct Stepper(Mutex<Option<Task>>);
+
+  impl Stepper {
+    fn step(&self) -> Option<Wait> {
+      self.0.lock().unwrap().as_mut().unwrap()()
+    }
+  }
+
+  impl Scheduler for Stepper {
+    fn spawn(&self, _: Option<String>, _: Arc<dyn Clock>, _: Writes, task: Task) -> io::Result<()> {
+      *self.0.lock().unwrap() = Some(task);
+      Ok(())
+    }
+  }
+
+  #[test]
+  fn test() {
+    let clock = Arc::new(ManualClock::new(SystemTime::UNIX_EPOCH));
+    let stepper = Arc::new(Stepper::default());
+    let saves = Arc::new(AtomicU64::new(0));
+    let s = saves.clone();
+    let quiet = Duration::from_secs(60);
+    let db = Database::builder()
+      .save_policy(SavePolicy::Debounced(quiet))
+      .clock(clock.clone())
+      .scheduler(stepper.clone())
+      .build(0u32, move |_| {
+        s.fetch_add(1, Ordering::Relaxed);
+      })
+      .unwrap();
+    *db.get_mut() = 1;
+    assert_eq!(stepper.step(), Some(Wait::Sleep(quiet)));
+    *db.get_mut() = 2;
+    assert_eq!(stepper.step(), Some(Wait::Sleep(quiet)));
+    assert_eq!(saves.load(Ordering::Relaxed), 0);
+    clock.advance(quiet);
+    assert_eq!(stepper.step(), Some(Wait::Write));
+    assert_eq!(saves.load(Ordering::Relaxed), 1);
+    assert_eq!(db.metadata().modified, SystemTime::UNIX_EPOCH + quiet);
+    assert_eq!(
+      db.saver_health().last_tick,
+      Some(SystemTime::UNIX_EPOCH + quiet)
+    );
+    assert_eq!(stepper.step(), Some(Wait::Write));
+    drop(db);
+    assert_eq!(stepper.step(), None);
+  }
+}
@@ -0,0 +1,61 @@
+use std::sync::mpsc;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::Database;
+
+/// Called with the data after every write, returns false once it should be removed.
+pub(crate) type Watcher<T> = Box<dyn FnMut(&T) -> bool + Send>;
+
+impl<T: Serialize + DeserializeOwned + Send + Sync + 'static> Database<T> {
+  /// Sends the value `f` projects from the data whenever a write changes it.
+  ///
+  /// `f` runs after every write while the data is still locked, so it should be cheap. The
+  /// watcher is removed after the receiver is dropped.
+  pub fn watch_map<U, F>(&self, f: F) -> mpsc::Receiver<U>
+  where
+    U: PartialEq + Clone + Send + 'static,
+    F: Fn(&T) -> U + Send + 'static,
+  {
+    let (tx, rx) = mpsc::channel();
+    let inner = self.0.read().unwrap();
+    let mut last = f(&inner.data);
+    inner.watchers.lock().unwrap().push(Box::new(move |data| {
+      let value = f(data);
+      if value == last {
+        return true;
+      }
+      last = value.clone();
+      tx.send(value).is_ok()
+    }));
+    rx
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use serde::{Serialize, Deserialize};
+  use super::*;
+
+  #[derive(Serialize, Deserialize)]
+  struct Settings {
+    theme: String,
+    volume: u32,
+  }
+
+  #[test]
+  fn test() {
+    let settings = Settings {
+      theme: "light".to_string(),
+      volume: 0,
+    };
+    let db = Database::new_custom(settings, |_| {});
+    let themes = db.watch_map(|s| s.theme.clone());
+    db.get_mut().volume = 1;
+    db.get_mut().theme = "dark".to_string();
+    db.get_mut().volume = 2;
+    assert_eq!(themes.try_iter().collect::<Vec<_>>(), ["dark"]);
+
+    drop(themes);
+    db.get_mut().theme = "light".to_string();
+    assert!(db.0.read().unwrap().watchers.lock().unwrap().is_empty());
+  }
+}
@@ -0,0 +1,57 @@
+use std::path::Path;
+use serde::{Serialize, de::DeserializeOwned};
+use crate::{Builder, Database, DataError};
+
+impl<T: Serialize + DeserializeOwned + Default + Send + Sync + 'static> Builder<T> {
+  /// Opens a database at `path` that is saved as plain YAML, so it can be read and patched by
+  /// hand. Edits made while the database is open are overwritten by its next save.
+  ///
+  /// Opening fails on duplicate keys instead of keeping the last one, so a botched edit doesn't
+  /// silently lose data. The file has no header, so the metadata isn't stored, and the format
+  /// options and delta saves fail with [`DataError::Unsupported`].
+  pub fn open_yaml<P: AsRef<Path>>(self, path: P) -> Result<Database<T>, DataError> {
+    self.open_encoded(
+      path.as_ref(),
+      true,
+      |payload| {
+        // a Value rejects duplicate keys in any map, a struct only rejects duplicate fields
+        let value: serde_yaml::Value = serde_yaml::from_slice(payload)?;
+        Ok(serde_yaml::from_value(value)?)
+      },
+      |data| Ok(serde_yaml::to_string(data)?.into_bytes()),
+    )
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use std::collections::HashMap;
+  use std::fs;
+  use serde::Deserialize;
+  use super::*;
+
+  #[derive(Serialize, Deserialize, Default)]
+  struct Test {
+    limits: HashMap<String, u32>,
+  }
+
+  #[test]
+  fn test() {
+    let path = std::env::temp_dir().join("floppadb-yaml.db");
+    let _ = fs::remove_file(&path);
+    let db = Database::<Test>::builder()
+      .manual_save()
+      .open_yaml(&path)
+      .unwrap();
+    db.get_mut().limits.insert("floppa".to_string(), 1);
+    db.flush().unwrap();
+    assert_eq!(fs::read_to_string(&path).unwrap(), "limits:\n  floppa: 1\n");
+    assert_eq!(db.to_bytes().unwrap(), fs::read(&path).unwrap());
+
+    fs::write(&path, "limits:\n  floppa: 1\n  floppa: 2\n").unwrap();
+    assert!(matches!(
+      Database::<Test>::builder().open_yaml(&path),
+      Err(DataError::Yaml(_))
+    ));
+  }
+}